@@ -0,0 +1,93 @@
+/*
+Times the SIMD distance kernels against the portable loops.
+
+    cargo bench --bench distances [-- dimension ...]
+
+Prints the time per call of each, and how many times faster the SIMD one is.
+*/
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::hint::black_box;
+use std::time::Instant;
+use vite::simd::{self, portable};
+
+fn time<F: FnMut() -> f64>(rounds: usize, mut f: F) -> f64 {
+    let start = Instant::now();
+    for _i in 0..rounds {
+        black_box(f());
+    }
+    start.elapsed().as_nanos() as f64 / rounds as f64
+}
+
+fn main() {
+    // cargo passes --bench, anything that is a number is a dimension
+    let mut dimensions: Vec<usize> = std::env::args().filter_map(|a| a.parse().ok()).collect();
+    if dimensions.is_empty() {
+        dimensions = vec![4, 8, 17, 32, 128, 768];
+    }
+
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let rounds = 200_000;
+    for dimension in dimensions {
+        let a: Vec<f64> = (0..dimension).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let b: Vec<f64> = (0..dimension).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let a32: Vec<f32> = a.iter().map(|&x| x as f32).collect();
+        let b32: Vec<f32> = b.iter().map(|&x| x as f32).collect();
+        let (a, b, a32, b32) = (&a[..], &b[..], &a32[..], &b32[..]);
+
+        let runs: [(&str, f64, f64); 6] = [
+            (
+                "dot f64",
+                time(rounds, || portable::dot(black_box(a), black_box(b))),
+                time(rounds, || simd::dot_f64(black_box(a), black_box(b))),
+            ),
+            (
+                "dot f32",
+                time(rounds, || portable::dot(black_box(a32), black_box(b32))),
+                time(rounds, || simd::dot_f32(black_box(a32), black_box(b32))),
+            ),
+            (
+                "l2 f64",
+                time(rounds, || portable::l2_squared(black_box(a), black_box(b))),
+                time(rounds, || simd::l2_squared_f64(black_box(a), black_box(b))),
+            ),
+            (
+                "l2 f32",
+                time(rounds, || {
+                    portable::l2_squared(black_box(a32), black_box(b32))
+                }),
+                time(rounds, || {
+                    simd::l2_squared_f32(black_box(a32), black_box(b32))
+                }),
+            ),
+            (
+                "cosine f64",
+                time(rounds, || {
+                    portable::cosine_parts(black_box(a), black_box(b)).0
+                }),
+                time(rounds, || {
+                    simd::cosine_parts_f64(black_box(a), black_box(b)).0
+                }),
+            ),
+            (
+                "cosine f32",
+                time(rounds, || {
+                    portable::cosine_parts(black_box(a32), black_box(b32)).0
+                }),
+                time(rounds, || {
+                    simd::cosine_parts_f32(black_box(a32), black_box(b32)).0
+                }),
+            ),
+        ];
+        for (name, portable, simd) in runs {
+            println!(
+                "d={}\t{}\tportable {:.1}ns\tsimd {:.1}ns\t{:.2}x",
+                dimension,
+                name,
+                portable,
+                simd,
+                portable / simd
+            );
+        }
+    }
+}
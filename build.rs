@@ -0,0 +1,25 @@
+fn main() {
+    // the grpc service is generated from proto/vite.proto with a vendored protoc
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/vite.proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_prost_build::compile_protos("proto/vite.proto").unwrap();
+    }
+
+    // the header is generated from src/capi.rs into OUT_DIR, a capi test checks
+    // that include/vlite.h is the same, the build never writes to the source tree
+    #[cfg(feature = "capi")]
+    {
+        println!("cargo:rerun-if-changed=src/capi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let out = std::env::var("OUT_DIR").unwrap();
+        cbindgen::Builder::new()
+            .with_config(cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).unwrap())
+            .with_src(format!("{}/src/capi.rs", dir))
+            .generate()
+            .unwrap()
+            .write_to_file(format!("{}/vlite.h", out));
+    }
+}
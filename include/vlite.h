@@ -0,0 +1,142 @@
+/* generated from src/capi.rs by cbindgen, `cargo test --features capi` fails when this file is out of date */
+
+#ifndef VLITE_H
+#define VLITE_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum VliteStatus {
+  VLITE_STATUS_OK = 0,
+  VLITE_STATUS_NULL_POINTER = 1,
+  VLITE_STATUS_INVALID_ARGUMENT = 2,
+  VLITE_STATUS_DIMENSION_MISMATCH = 3,
+  VLITE_STATUS_IO_ERROR = 4,
+  VLITE_STATUS_NOT_FOUND = 5,
+  VLITE_STATUS_PANIC = 6,
+} VliteStatus;
+
+typedef struct VliteGraph VliteGraph;
+
+typedef struct VliteResult {
+  uint64_t id;
+  double distance;
+} VliteResult;
+
+typedef struct VliteResults {
+  struct VliteResult *results;
+  size_t len;
+} VliteResults;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a graph holding `first`. `metric` is "cosine", "l2" or "dot" and
+ * `element_type` "f64", "f32", "f16" or "bf16", null picks cosine and f64.
+ * The same `seed` and inserts build the same graph.
+ *
+ * # Safety
+ * `first` points to `dimension` doubles, the strings are null or nul terminated.
+ */
+enum VliteStatus vlite_new(const double *first,
+                           size_t dimension,
+                           double m,
+                           size_t m_max,
+                           size_t m_max0,
+                           size_t candidate_list_size,
+                           const char *metric,
+                           const char *element_type,
+                           uint64_t seed,
+                           struct VliteGraph **out);
+
+/**
+ * Reads a collection from a .vlite file, null `collection` is "default".
+ *
+ * # Safety
+ * The strings are null or nul terminated, `out` is writable.
+ */
+enum VliteStatus vlite_open(const char *path, const char *collection, struct VliteGraph **out);
+
+/**
+ * Inserts a vector, its id is written to `id` when that isn't null.
+ *
+ * # Safety
+ * `graph` came from `vlite_new` or `vlite_open`, `vector` points to `dimension` doubles.
+ */
+enum VliteStatus vlite_insert(struct VliteGraph *graph,
+                              const double *vector,
+                              size_t dimension,
+                              uint64_t *id);
+
+/**
+ * Finds the `k` nearest nodes with a beam of `ef`, 0 picks 20.
+ *
+ * # Safety
+ * `graph` came from `vlite_new` or `vlite_open`, `vector` points to `dimension` doubles.
+ */
+enum VliteStatus vlite_search(const struct VliteGraph *graph,
+                              const double *vector,
+                              size_t dimension,
+                              size_t k,
+                              size_t ef,
+                              struct VliteResults *out);
+
+/**
+ * Frees what `vlite_search` returned and empties `results`.
+ *
+ * # Safety
+ * `results` is null or was filled by `vlite_search` and not freed since.
+ */
+void vlite_free_results(struct VliteResults *results);
+
+/**
+ * Writes the graph as a collection of a .vlite file, keeping the file's other
+ * collections. Null `collection` is "default".
+ *
+ * # Safety
+ * `graph` came from `vlite_new` or `vlite_open`, the strings are null or nul terminated.
+ */
+enum VliteStatus vlite_save(const struct VliteGraph *graph,
+                            const char *path,
+                            const char *collection);
+
+/**
+ * The length of the graph's vectors, 0 for a null graph.
+ *
+ * # Safety
+ * `graph` is null or came from `vlite_new` or `vlite_open`.
+ */
+size_t vlite_dimension(const struct VliteGraph *graph);
+
+/**
+ * The number of nodes in the graph, deleted ones included, 0 for a null graph.
+ *
+ * # Safety
+ * `graph` is null or came from `vlite_new` or `vlite_open`.
+ */
+size_t vlite_len(const struct VliteGraph *graph);
+
+/**
+ * Frees the graph, nothing is saved.
+ *
+ * # Safety
+ * `graph` is null or came from `vlite_new` or `vlite_open` and isn't used afterwards.
+ */
+void vlite_close(struct VliteGraph *graph);
+
+/**
+ * A static description of a status, never null. Takes the status as an int so
+ * values that aren't a `VliteStatus` are safe to pass, they are "unknown status".
+ */
+const char *vlite_status_message(int status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* VLITE_H */
@@ -0,0 +1,395 @@
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use crate::file::{write_collection, GraphFile, DEFAULT_COLLECTION};
+use crate::hnsw::{knn_search, Graph};
+use crate::metric::Metric;
+use crate::vector::ElementType;
+
+/*
+A C API over `Graph` and `GraphFile`, see include/vlite.h. Graphs are opaque
+pointers from `vlite_new` or `vlite_open` and must be given back to
+`vlite_close`. Every call that can fail returns a `VliteStatus`, outputs are
+written through pointers only on `VLITE_OK`. A graph must not be used from two
+threads at once.
+*/
+pub struct VliteGraph {
+    g: Box<Graph>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VliteStatus {
+    Ok = 0,
+    // a required pointer was null
+    NullPointer = 1,
+    // a string wasn't utf8, or a metric or element type is unknown
+    InvalidArgument = 2,
+    // the vector doesn't have the graph's dimension
+    DimensionMismatch = 3,
+    // the file couldn't be read or written
+    IoError = 4,
+    // the file has no such collection
+    NotFound = 5,
+    // a bug in vlite, the graph should not be used anymore
+    Panic = 6,
+}
+
+#[repr(C)]
+pub struct VliteResult {
+    pub id: u64,
+    pub distance: f64,
+}
+
+// nearest first, free with `vlite_free_results`
+#[repr(C)]
+pub struct VliteResults {
+    pub results: *mut VliteResult,
+    pub len: usize,
+}
+
+fn guard<F: FnOnce() -> Result<(), VliteStatus>>(f: F) -> VliteStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => VliteStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => VliteStatus::Panic,
+    }
+}
+
+// a null string is `default`
+unsafe fn str_or(s: *const c_char, default: &str) -> Result<&str, VliteStatus> {
+    if s.is_null() {
+        return Ok(default);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| VliteStatus::InvalidArgument)
+}
+
+unsafe fn slice<'a>(v: *const f64, dimension: usize) -> Result<&'a [f64], VliteStatus> {
+    if v.is_null() {
+        return Err(VliteStatus::NullPointer);
+    }
+    Ok(std::slice::from_raw_parts(v, dimension))
+}
+
+/// Creates a graph holding `first`. `metric` is "cosine", "l2" or "dot" and
+/// `element_type` "f64", "f32", "f16" or "bf16", null picks cosine and f64.
+/// The same `seed` and inserts build the same graph.
+///
+/// # Safety
+/// `first` points to `dimension` doubles, the strings are null or nul terminated.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_new(
+    first: *const f64,
+    dimension: usize,
+    m: f64,
+    m_max: usize,
+    m_max0: usize,
+    candidate_list_size: usize,
+    metric: *const c_char,
+    element_type: *const c_char,
+    seed: u64,
+    out: *mut *mut VliteGraph,
+) -> VliteStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(VliteStatus::NullPointer);
+        }
+        let first = slice(first, dimension)?;
+        if first.is_empty() {
+            return Err(VliteStatus::DimensionMismatch);
+        }
+        let metric =
+            Metric::parse(str_or(metric, "cosine")?).ok_or(VliteStatus::InvalidArgument)?;
+        let element_type =
+            ElementType::parse(str_or(element_type, "f64")?).ok_or(VliteStatus::InvalidArgument)?;
+        let g = Graph::new(
+            first,
+            m,
+            m_max,
+            m_max0,
+            candidate_list_size,
+            metric,
+            element_type,
+            seed,
+        )
+        .map_err(|_| VliteStatus::InvalidArgument)?;
+        *out = Box::into_raw(Box::new(VliteGraph { g: Box::new(g) }));
+        Ok(())
+    })
+}
+
+/// Reads a collection from a .vlite file, null `collection` is "default".
+///
+/// # Safety
+/// The strings are null or nul terminated, `out` is writable.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_open(
+    path: *const c_char,
+    collection: *const c_char,
+    out: *mut *mut VliteGraph,
+) -> VliteStatus {
+    guard(|| {
+        if path.is_null() || out.is_null() {
+            return Err(VliteStatus::NullPointer);
+        }
+        let path = str_or(path, "")?;
+        let collection = str_or(collection, DEFAULT_COLLECTION)?;
+        let mut g = GraphFile::open(path.to_string())
+            .map_err(|_| VliteStatus::IoError)?
+            .read_collection(collection)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => VliteStatus::NotFound,
+                _ => VliteStatus::IoError,
+            })?;
+        g.try_weaken_ep()
+            .and_then(|()| g.try_link_friends())
+            .map_err(|_| VliteStatus::IoError)?;
+        *out = Box::into_raw(Box::new(VliteGraph { g }));
+        Ok(())
+    })
+}
+
+/// Inserts a vector, its id is written to `id` when that isn't null.
+///
+/// # Safety
+/// `graph` came from `vlite_new` or `vlite_open`, `vector` points to `dimension` doubles.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_insert(
+    graph: *mut VliteGraph,
+    vector: *const f64,
+    dimension: usize,
+    id: *mut u64,
+) -> VliteStatus {
+    guard(|| {
+        let graph = graph.as_mut().ok_or(VliteStatus::NullPointer)?;
+        let q = slice(vector, dimension)?;
+        if q.len() != graph.g.dimension {
+            return Err(VliteStatus::DimensionMismatch);
+        }
+        let inserted = graph.g.insert(q);
+        if !id.is_null() {
+            *id = inserted as u64;
+        }
+        Ok(())
+    })
+}
+
+/// Finds the `k` nearest nodes with a beam of `ef`, 0 picks 20.
+///
+/// # Safety
+/// `graph` came from `vlite_new` or `vlite_open`, `vector` points to `dimension` doubles.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_search(
+    graph: *const VliteGraph,
+    vector: *const f64,
+    dimension: usize,
+    k: usize,
+    ef: usize,
+    out: *mut VliteResults,
+) -> VliteStatus {
+    guard(|| {
+        let graph = graph.as_ref().ok_or(VliteStatus::NullPointer)?;
+        if out.is_null() {
+            return Err(VliteStatus::NullPointer);
+        }
+        let q = slice(vector, dimension)?;
+        if q.len() != graph.g.dimension {
+            return Err(VliteStatus::DimensionMismatch);
+        }
+        let ef = if ef == 0 { 20 } else { ef };
+        let results: Box<[VliteResult]> = knn_search(&graph.g, q, k, ef)
+            .into_iter()
+            .map(|r| VliteResult {
+                id: r.id as u64,
+                distance: r.distance,
+            })
+            .collect();
+        let len = results.len();
+        *out = VliteResults {
+            results: Box::into_raw(results) as *mut VliteResult,
+            len,
+        };
+        Ok(())
+    })
+}
+
+/// Frees what `vlite_search` returned and empties `results`.
+///
+/// # Safety
+/// `results` is null or was filled by `vlite_search` and not freed since.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_free_results(results: *mut VliteResults) {
+    if let Some(results) = results.as_mut() {
+        if !results.results.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                results.results,
+                results.len,
+            )));
+        }
+        results.results = ptr::null_mut();
+        results.len = 0;
+    }
+}
+
+/// Writes the graph as a collection of a .vlite file, keeping the file's other
+/// collections. Null `collection` is "default".
+///
+/// # Safety
+/// `graph` came from `vlite_new` or `vlite_open`, the strings are null or nul terminated.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_save(
+    graph: *const VliteGraph,
+    path: *const c_char,
+    collection: *const c_char,
+) -> VliteStatus {
+    guard(|| {
+        let graph = graph.as_ref().ok_or(VliteStatus::NullPointer)?;
+        if path.is_null() {
+            return Err(VliteStatus::NullPointer);
+        }
+        let path = str_or(path, "")?;
+        let collection = str_or(collection, DEFAULT_COLLECTION)?;
+        write_collection(path, collection, &graph.g).map_err(|_| VliteStatus::IoError)
+    })
+}
+
+/// The length of the graph's vectors, 0 for a null graph.
+///
+/// # Safety
+/// `graph` is null or came from `vlite_new` or `vlite_open`.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_dimension(graph: *const VliteGraph) -> usize {
+    graph.as_ref().map_or(0, |graph| graph.g.dimension)
+}
+
+/// The number of nodes in the graph, deleted ones included, 0 for a null graph.
+///
+/// # Safety
+/// `graph` is null or came from `vlite_new` or `vlite_open`.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_len(graph: *const VliteGraph) -> usize {
+    graph.as_ref().map_or(0, |graph| graph.g.nodes.len())
+}
+
+/// Frees the graph, nothing is saved.
+///
+/// # Safety
+/// `graph` is null or came from `vlite_new` or `vlite_open` and isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn vlite_close(graph: *mut VliteGraph) {
+    if !graph.is_null() {
+        drop(Box::from_raw(graph));
+    }
+}
+
+/// A static description of a status, never null. Takes the status as an int so
+/// values that aren't a `VliteStatus` are safe to pass, they are "unknown status".
+#[no_mangle]
+pub extern "C" fn vlite_status_message(status: c_int) -> *const c_char {
+    let message: &'static CStr = match status {
+        0 => c"ok",
+        1 => c"a required pointer was null",
+        2 => c"invalid argument",
+        3 => c"the vector doesn't have the graph's dimension",
+        4 => c"the file couldn't be read or written",
+        5 => c"no such collection",
+        6 => c"internal error",
+        _ => c"unknown status",
+    };
+    message.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use std::ffi::CString;
+
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/vlite.h"));
+        assert!(
+            generated == include_str!("../include/vlite.h"),
+            "include/vlite.h is out of date, copy {}/vlite.h over it",
+            env!("OUT_DIR")
+        );
+    }
+
+    #[test]
+    fn unknown_statuses_have_a_message() {
+        let message = |status| unsafe { CStr::from_ptr(vlite_status_message(status)) };
+        assert_eq!(message(VliteStatus::Panic as c_int), c"internal error");
+        assert_eq!(message(7), c"unknown status");
+        assert_eq!(message(-1), c"unknown status");
+    }
+
+    #[test]
+    fn graphs() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut g = ptr::null_mut();
+        let first = [0.0, 1.0, 0.0];
+        let path = temp_path("capi.vlite");
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let status = vlite_new(
+                first.as_ptr(),
+                3,
+                4.0,
+                16,
+                32,
+                64,
+                c"l2".as_ptr(),
+                ptr::null(),
+                7,
+                &mut g,
+            );
+            assert_eq!(status, VliteStatus::Ok);
+            for i in 1..50 {
+                let vec: [f64; 3] = rng.gen();
+                let mut id = 0;
+                assert_eq!(vlite_insert(g, vec.as_ptr(), 3, &mut id), VliteStatus::Ok);
+                assert_eq!(id, i);
+            }
+            let status = vlite_insert(g, first.as_ptr(), 2, ptr::null_mut());
+            assert_eq!(status, VliteStatus::DimensionMismatch);
+            let status = vlite_insert(ptr::null_mut(), first.as_ptr(), 3, ptr::null_mut());
+            assert_eq!(status, VliteStatus::NullPointer);
+
+            let mut results = VliteResults {
+                results: ptr::null_mut(),
+                len: 0,
+            };
+            assert_eq!(
+                vlite_search(g, first.as_ptr(), 3, 5, 0, &mut results),
+                VliteStatus::Ok
+            );
+            assert_eq!(results.len, 5);
+            assert_eq!((*results.results).id, 0);
+            vlite_free_results(&mut results);
+            assert!(results.results.is_null());
+
+            assert_eq!(vlite_save(g, path.as_ptr(), c"c".as_ptr()), VliteStatus::Ok);
+            vlite_close(g);
+            assert_eq!(
+                vlite_open(path.as_ptr(), ptr::null(), &mut g),
+                VliteStatus::NotFound
+            );
+            assert_eq!(
+                vlite_open(path.as_ptr(), c"c".as_ptr(), &mut g),
+                VliteStatus::Ok
+            );
+            std::fs::remove_file(path.to_str().unwrap()).unwrap();
+            assert_eq!((vlite_len(g), vlite_dimension(g)), (50, 3));
+            vlite_close(g);
+            assert_eq!(
+                vlite_open(path.as_ptr(), ptr::null(), &mut g),
+                VliteStatus::IoError
+            );
+        }
+    }
+}
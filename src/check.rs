@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::hnsw::Graph;
+
+/*
+Structural invariants of a `Graph`, checked after it has been read from a file
+(friends may still be indices) or built in memory (friends are pointers).
+*/
+pub enum Violation {
+    // node stored at `position` in `Graph::nodes` thinks it lives somewhere else
+    IndexMismatch {
+        position: usize,
+        index: usize,
+    },
+    // friend id does not point at a node in the graph
+    MissingNeighbor {
+        node: usize,
+        layer: usize,
+        friend: usize,
+    },
+    // friend exists but does not live on `layer`
+    NeighborBelowLayer {
+        node: usize,
+        layer: usize,
+        friend: usize,
+    },
+    SelfLoop {
+        node: usize,
+        layer: usize,
+    },
+    // friend_layers.len() != max_level + 1
+    LayerMismatch {
+        node: usize,
+        layers: usize,
+        max_level: usize,
+    },
+    // more friends than m_max (or m_max0 on layer 0)
+    LayerOverfull {
+        node: usize,
+        layer: usize,
+        friends: usize,
+        max: usize,
+    },
+    // codes that the graph's quantizer couldn't have written, or none in a codes only graph
+    CodeMismatch {
+        node: usize,
+        codes: usize,
+    },
+    MissingEntrencePoint,
+    // entrence point is not on the top layer
+    EntrencePointLevel {
+        node: usize,
+        max_level: usize,
+        top: usize,
+    },
+    // layer_count does not match the highest node level
+    LayerCount {
+        layer_count: usize,
+        expected: usize,
+    },
+    // the graph couldn't be read at all, nothing else was checked
+    Unreadable {
+        error: String,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::IndexMismatch { position, index } => {
+                write!(f, "node at position {} has index {}", position, index)
+            }
+            Violation::MissingNeighbor {
+                node,
+                layer,
+                friend,
+            } => write!(
+                f,
+                "node {} layer {}: friend {} does not exist",
+                node, layer, friend
+            ),
+            Violation::NeighborBelowLayer {
+                node,
+                layer,
+                friend,
+            } => write!(
+                f,
+                "node {} layer {}: friend {} is not on layer {}",
+                node, layer, friend, layer
+            ),
+            Violation::SelfLoop { node, layer } => {
+                write!(f, "node {} layer {}: friend of itself", node, layer)
+            }
+            Violation::LayerMismatch {
+                node,
+                layers,
+                max_level,
+            } => write!(
+                f,
+                "node {}: {} friend layers but max level {}",
+                node, layers, max_level
+            ),
+            Violation::LayerOverfull {
+                node,
+                layer,
+                friends,
+                max,
+            } => write!(
+                f,
+                "node {} layer {}: {} friends, max is {}",
+                node, layer, friends, max
+            ),
+            Violation::CodeMismatch { node, codes } => write!(
+                f,
+                "node {}: {} bytes of codes don't fit the quantizer",
+                node, codes
+            ),
+            Violation::MissingEntrencePoint => write!(f, "entrence point does not exist"),
+            Violation::EntrencePointLevel {
+                node,
+                max_level,
+                top,
+            } => write!(
+                f,
+                "entrence point {} has max level {} but the top layer is {}",
+                node, max_level, top
+            ),
+            Violation::LayerCount {
+                layer_count,
+                expected,
+            } => write!(
+                f,
+                "layer count is {} but the highest node needs {}",
+                layer_count, expected
+            ),
+            Violation::Unreadable { error } => write!(f, "the graph could not be read: {}", error),
+        }
+    }
+}
+
+pub fn check(g: &Graph) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    let mut expected_layers = 0;
+    for (position, node) in g.nodes.iter().enumerate() {
+        let node = node.borrow();
+        if node.index != position {
+            violations.push(Violation::IndexMismatch {
+                position,
+                index: node.index,
+            });
+        }
+
+        let codes_fit = match &g.quantizer {
+            Some(quantizer) => quantizer.fits(&node.codes, g.dimension),
+            None => node.codes.is_empty(),
+        };
+        // a node without its vector has nothing but codes to be found by
+        if !codes_fit || (g.codes_only && node.codes.is_empty()) {
+            violations.push(Violation::CodeMismatch {
+                node: position,
+                codes: node.codes.len(),
+            });
+        }
+
+        expected_layers = expected_layers.max(node.max_level + 1);
+        if node.friend_layers.len() != node.max_level + 1 {
+            violations.push(Violation::LayerMismatch {
+                node: position,
+                layers: node.friend_layers.len(),
+                max_level: node.max_level,
+            });
+        }
+
+        for (layer, friends) in node.friend_layers.iter().enumerate() {
+            let max = if layer > 0 { g.m_max } else { g.m_max0 };
+            if friends.len() > max {
+                violations.push(Violation::LayerOverfull {
+                    node: position,
+                    layer,
+                    friends: friends.len(),
+                    max,
+                });
+            }
+
+            for friend in friends {
+                let id = friend.id();
+                if id == position {
+                    violations.push(Violation::SelfLoop {
+                        node: position,
+                        layer,
+                    });
+                } else if id >= g.nodes.len() {
+                    violations.push(Violation::MissingNeighbor {
+                        node: position,
+                        layer,
+                        friend: id,
+                    });
+                } else if g.nodes[id].borrow().max_level < layer {
+                    violations.push(Violation::NeighborBelowLayer {
+                        node: position,
+                        layer,
+                        friend: id,
+                    });
+                }
+            }
+        }
+    }
+
+    if g.layer_count != expected_layers {
+        violations.push(Violation::LayerCount {
+            layer_count: g.layer_count,
+            expected: expected_layers,
+        });
+    }
+
+    let ep = match g.entrence_point.id() {
+        Some(ep) if ep < g.nodes.len() => ep,
+        _ => {
+            violations.push(Violation::MissingEntrencePoint);
+            return violations;
+        }
+    };
+
+    let max_level = g.nodes[ep].borrow().max_level;
+    if max_level + 1 != g.layer_count {
+        violations.push(Violation::EntrencePointLevel {
+            node: ep,
+            max_level,
+            top: g.layer_count.saturating_sub(1),
+        });
+    }
+
+    violations
+}
+
+/*
+nodes that can't be reached from `ep` by walking layer 0
+pruning a full neighbor list can drop the last link into a node, the neighbor
+selection keeps that rare but doesn't rule it out, so these are warnings and
+not violations
+*/
+pub fn unreachable(g: &Graph, ep: usize) -> Vec<usize> {
+    let mut seen = vec![false; g.nodes.len()];
+    let mut queue = VecDeque::new();
+    seen[ep] = true;
+    queue.push_back(ep);
+
+    while let Some(current) = queue.pop_front() {
+        let node = g.nodes[current].borrow();
+        if let Some(friends) = node.friend_layers.first() {
+            for friend in friends {
+                let id = friend.id();
+                if id < seen.len() && !seen[id] {
+                    seen[id] = true;
+                    queue.push_back(id);
+                }
+            }
+        }
+    }
+
+    seen.iter()
+        .enumerate()
+        .filter(|(_, &seen)| !seen)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnsw::tests::random_graph;
+    use crate::hnsw::{EntrencePoint, NodePtr};
+    use crate::metric::Metric;
+    use crate::vector::ElementType;
+
+    #[test]
+    fn broken_graphs_are_reported() {
+        let g = random_graph(4, 200, Metric::Cosine, ElementType::F64, 1);
+        assert!(check(&g).is_empty());
+
+        let g = random_graph(4, 200, Metric::Cosine, ElementType::F64, 1);
+        g.nodes[3].borrow_mut().friend_layers[0].push(NodePtr::Index(1000));
+        g.nodes[4].borrow_mut().friend_layers[0].push(NodePtr::Index(4));
+        g.nodes[5].borrow_mut().friend_layers.push(Vec::new());
+        g.nodes[6].borrow_mut().index = 7;
+        let violations = check(&g);
+        assert!(violations.iter().any(|v| matches!(
+            v,
+            Violation::MissingNeighbor {
+                node: 3,
+                friend: 1000,
+                ..
+            }
+        )));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::SelfLoop { node: 4, layer: 0 })));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::LayerMismatch { node: 5, .. })));
+        assert!(violations.iter().any(|v| matches!(
+            v,
+            Violation::IndexMismatch {
+                position: 6,
+                index: 7
+            }
+        )));
+
+        let mut g = random_graph(4, 200, Metric::Cosine, ElementType::F64, 1);
+        g.layer_count += 1;
+        let violations = check(&g);
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::LayerCount { .. })));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::EntrencePointLevel { .. })));
+
+        g.entrence_point = EntrencePoint::Index(1000);
+        assert!(check(&g)
+            .iter()
+            .any(|v| matches!(v, Violation::MissingEntrencePoint)));
+    }
+}
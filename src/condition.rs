@@ -0,0 +1,388 @@
+use std::fmt;
+
+use crate::filter::Filter;
+use crate::hnsw::Graph;
+use crate::payload::{Payload, Value};
+
+/*
+Conditions on node payloads, written like
+    category = "shoes" AND price < 50 AND tags CONTAINS "sale"
+
+grammar, keywords are case insensitive:
+    or         := and (OR and)*
+    and        := not (AND not)*
+    not        := NOT not | '(' or ')' | comparison
+    comparison := field op literal | field CONTAINS string
+    op         := = | == | != | < | <= | > | >=
+    literal    := "string" | number | true | false
+
+A comparison against a missing field, or a value of another kind, is false.
+NOT and parentheses nest at most MAX_DEPTH deep, and a run of ORs or ANDs is
+built as a balanced tree, so no condition is deep enough to overflow the stack.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Compare(String, Op, Literal),
+    Contains(String, String),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+            depth: 0,
+        };
+        let condition = parser.or()?;
+        match parser.peek() {
+            None => Ok(condition),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    pub fn matches(&self, payload: &Payload) -> bool {
+        match self {
+            Condition::Compare(field, op, literal) => match payload.fields.get(field) {
+                Some(value) => compare(value, *op, literal),
+                None => false,
+            },
+            Condition::Contains(field, needle) => match payload.fields.get(field) {
+                Some(Value::Tags(tags)) => tags.iter().any(|tag| tag == needle),
+                Some(Value::String(s)) => s.contains(needle.as_str()),
+                _ => false,
+            },
+            Condition::Not(c) => !c.matches(payload),
+            Condition::And(a, b) => a.matches(payload) && b.matches(payload),
+            Condition::Or(a, b) => a.matches(payload) || b.matches(payload),
+        }
+    }
+
+    // for `knn_search_filtered`, allows the nodes of `g` whose payload matches
+    pub fn filter<'a>(&'a self, g: &'a Graph) -> impl Filter + 'a {
+        move |id: usize| self.matches(&g.nodes[id].borrow().payload)
+    }
+}
+
+fn compare(value: &Value, op: Op, literal: &Literal) -> bool {
+    let ordering = match (value, literal) {
+        (Value::Number(a), Literal::Number(b)) => a.partial_cmp(b),
+        (Value::String(a), Literal::String(b)) => Some(a.as_str().cmp(b.as_str())),
+        (Value::Bool(a), Literal::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    match ordering {
+        Some(ordering) => match op {
+            Op::Eq => ordering.is_eq(),
+            Op::Ne => ordering.is_ne(),
+            Op::Lt => ordering.is_lt(),
+            Op::Le => ordering.is_le(),
+            Op::Gt => ordering.is_gt(),
+            Op::Ge => ordering.is_ge(),
+        },
+        None => false,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Number(f64),
+    Op(Op),
+    LParen,
+    RParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::String(s) => write!(f, "\"{}\"", s),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Op(op) => write!(f, "{:?}", op),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '=' | '!' | '<' | '>' => {
+                let (op, len) = match (c, next) {
+                    ('=', Some('=')) => (Op::Eq, 2),
+                    ('=', _) => (Op::Eq, 1),
+                    ('!', Some('=')) => (Op::Ne, 2),
+                    ('<', Some('=')) => (Op::Le, 2),
+                    ('<', _) => (Op::Lt, 1),
+                    ('>', Some('=')) => (Op::Ge, 2),
+                    ('>', _) => (Op::Gt, 1),
+                    _ => return Err("expected != after !".to_string()),
+                };
+                tokens.push(Token::Op(op));
+                i += len;
+            }
+            '"' => {
+                let mut string = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err("unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            string.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&c) => {
+                            string.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::String(string));
+                i += 1;
+            }
+            _ if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let start = i;
+                i += 1;
+                while i < chars.len()
+                    && (chars[i].is_ascii_digit()
+                        || chars[i] == '.'
+                        || chars[i] == 'e'
+                        || chars[i] == 'E'
+                        || ((chars[i] == '-' || chars[i] == '+')
+                            && (chars[i - 1] == 'e' || chars[i - 1] == 'E')))
+                {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                tokens.push(Token::Number(
+                    number
+                        .parse()
+                        .map_err(|_| format!("bad number {}", number))?,
+                ));
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => return Err(format!("unexpected character {}", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    // how many NOTs and parentheses the current token is inside
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.and()?];
+        while self.keyword("or") {
+            conditions.push(self.and()?);
+        }
+        Ok(balanced(conditions, Condition::Or))
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.not()?];
+        while self.keyword("and") {
+            conditions.push(self.not()?);
+        }
+        Ok(balanced(conditions, Condition::And))
+    }
+
+    fn not(&mut self) -> Result<Condition, String> {
+        if self.keyword("not") {
+            self.enter()?;
+            let condition = Condition::Not(Box::new(self.not()?));
+            self.depth -= 1;
+            return Ok(condition);
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.position += 1;
+            self.enter()?;
+            let condition = self.or()?;
+            self.depth -= 1;
+            return match self.next() {
+                Some(Token::RParen) => Ok(condition),
+                _ => Err("expected )".to_string()),
+            };
+        }
+        self.comparison()
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("conditions nest at most {} deep", MAX_DEPTH));
+        }
+        Ok(())
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let field = match self.next() {
+            Some(Token::Ident(field)) => field,
+            Some(token) => return Err(format!("expected a field name, found {}", token)),
+            None => return Err("expected a field name".to_string()),
+        };
+        if self.keyword("contains") {
+            return match self.next() {
+                Some(Token::String(s)) => Ok(Condition::Contains(field, s)),
+                _ => Err(format!("{}: CONTAINS needs a string", field)),
+            };
+        }
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(format!("{}: expected a comparison", field)),
+        };
+        let literal = match self.next() {
+            Some(Token::String(s)) => Literal::String(s),
+            Some(Token::Number(n)) => Literal::Number(n),
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case("true") => Literal::Bool(true),
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case("false") => Literal::Bool(false),
+            _ => return Err(format!("{}: expected a string, number or bool", field)),
+        };
+        Ok(Condition::Compare(field, op, literal))
+    }
+}
+
+// `a OR b OR c OR d` as (a OR b) OR (c OR d), the order they are tried in stays the same
+fn balanced(
+    mut conditions: Vec<Condition>,
+    join: fn(Box<Condition>, Box<Condition>) -> Condition,
+) -> Condition {
+    if conditions.len() == 1 {
+        return conditions.pop().unwrap();
+    }
+    let right = conditions.split_off(conditions.len() / 2);
+    join(
+        Box::new(balanced(conditions, join)),
+        Box::new(balanced(right, join)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions_match_payloads() {
+        let payload = Payload::from_json(&serde_json::json!({
+            "category": "shoes", "price": 40, "tags": ["sale", "new"], "stock": true
+        }))
+        .unwrap();
+        let cases = [
+            (
+                r#"category = "shoes" AND price < 50 AND tags CONTAINS "sale""#,
+                true,
+            ),
+            (r#"category = "hats" OR price >= 40"#, true),
+            (r#"not (stock = true) or price != 40"#, false),
+            (r#"tags contains "old" or category contains "sho""#, true),
+            (r#"missing = 1 or price = "40""#, false),
+            (r#"price > -1e2 and price <= 40.0"#, true),
+        ];
+        for (condition, expected) in cases {
+            assert_eq!(
+                Condition::parse(condition).unwrap().matches(&payload),
+                expected,
+                "{}",
+                condition
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_conditions_are_errors() {
+        for bad in [
+            "price <",
+            "price < 1 and",
+            "(price < 1",
+            "price ! 1",
+            r#"tags contains 1"#,
+        ] {
+            assert!(Condition::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn deep_conditions_are_errors() {
+        let nested = |depth: usize| format!("{}price < 50{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Condition::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Condition::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Condition::parse(&"(".repeat(100_000)).is_err());
+        assert!(Condition::parse(&format!("{}price < 50", "NOT ".repeat(100_000))).is_err());
+
+        // long runs of OR stay shallow enough to match and drop
+        let payload = Payload::from_json(&serde_json::json!({"price": 40})).unwrap();
+        let run = vec!["price = 1"; 100_000].join(" OR ") + " OR price = 40";
+        assert!(Condition::parse(&run).unwrap().matches(&payload));
+    }
+}
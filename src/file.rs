@@ -1,102 +1,612 @@
-use log::{error, info};
 #[allow(dead_code)]
 #[allow(unused_variables)]
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
 
 use crate::hnsw::{Graph, Node};
 
+pub const MAGIC: &[u8; 14] = b"vite format 2\0";
+// format 1 files hold a single graph right after the magic
+const MAGIC_V1: &[u8; 14] = b"vite format 1\0";
+// format 0 files have no graph data size, it is always 64 bytes
+const MAGIC_V0: &[u8; 14] = b"vite format 0\0";
+// what a file without a table of contents calls its graph
+pub const DEFAULT_COLLECTION: &str = "default";
+
 /*
 offset  size(b) description
 ----------------------------------------------
-0       14      vite format 0\000
---------- GRAPH DATA - 64b --------------------
-28      8       vector dimension
-36      8       graph size
-42      8       enternce point index
-50      4       layers
-54      8       m_l
-62      8       m_max
-70      8       m_max0
-78      4       canidate list size
+0       14      vite format 2\000
+14      4       table of contents size
+--------- TABLE OF CONTENTS -------------------
+18      8       number of collections
+        8+n     name, length then utf8        } repeated for
+        8       offset of the collection      } each collection
+        8       size of the collection        }
+
+------------- COLLECTION FORMAT ---------------
+offset  size(b) description
+0       4       graph data size
+--------- GRAPH DATA --------------------------
+4       8       vector dimension
+12      8       graph size
+20      8       enternce point index
+28      8       layers
+36      8       m_l
+44      8       m_max
+52      8       m_max0
+60      8       canidate list size
+68      1       metric (0 cosine, 1 l2, 2 dot)
+69      1       element type (0 f64, 1 f32, 2 f16, 3 bf16)
+70      1       quantizer (0 none, 1 int8, 2 pq, 3 binary)
+71      ...     quantizer data
+                  int8: 8*d min of each dimension, 8*d max of each dimension
+                  pq:   8 subspaces, 8 centroids, 8 codebook length n (0 untrained),
+                        8*n codebooks, subspace by subspace, centroid by centroid
+                  binary: 1 re-rank (0 no, 1 yes)
+        1       codes only (1 nodes have no vectors), missing from graphs written before it
+        8       rng seed, missing from graphs written before it was kept
 
 
 --------------- NODE FORMAT -------------------
 offset  size(b) description
 0       4       node size
 4       8       index
-12      4       layers
-16      8*d     vector
-        8       length of layer
-        8*n     index of friend
+12      8       max level
+20      e*d     vector, e is the size of the element type, empty in a codes only graph
+        8       length of layer       } repeated for each
+        8*n     index of friend       } layer up to max level
+        8       length of codes
+        c       quantizer codes
+        8       norm of the vector, worked out on load if missing
+        ...     payload, see `Payload::serialize`, empty if missing
+        1       flags (1 deleted, 2 has a key), 0 if missing
+        8+n     key, length then utf8, only with the has a key flag
+
+the nodes end with a node size of 0, the graph data is followed by the nodes
+
+format 1 files are a single collection right after the magic, format 0 files
+are the same without the graph data size
 */
 
-pub struct GraphFile {
-    file: File,
+// where a collection lives in the file
+pub struct Entry {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+// a .vlite file on disk, or any other bytes that can be read and seeked like one
+pub struct GraphFile<F = File> {
+    file: F,
 }
 
 impl GraphFile {
-    pub fn create(path: String) -> Self {
-        GraphFile {
-            file: File::create(path.clone()).unwrap(),
-        }
+    pub fn create(path: String) -> io::Result<Self> {
+        Ok(GraphFile {
+            file: File::create(path)?,
+        })
+    }
+
+    pub fn open(path: String) -> io::Result<Self> {
+        Ok(GraphFile {
+            file: File::open(path)?,
+        })
     }
+}
 
-    pub fn open(path: String) -> Self {
+impl<'a> GraphFile<Cursor<&'a [u8]>> {
+    // a whole file already in memory, like one fetched by a browser
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
         GraphFile {
-            file: File::open(path.clone()).unwrap(),
+            file: Cursor::new(bytes),
         }
     }
+}
 
-    pub fn write(&mut self, g: &Graph) -> std::io::Result<()> {
-        self.file.write(b"vite format 0\0")?;
-        self.file.write(&*g.serialize())?;
-        g.nodes
+impl<F: Write> GraphFile<F> {
+    pub fn write_all(&mut self, collections: &[(&str, &Graph)]) -> std::io::Result<()> {
+        let bodies: Vec<Vec<u8>> = collections
             .iter()
-            .for_each(|n| match self.file.write(&*n.borrow().serialize()) {
-                Ok(_value) => info!("writing successful"),
-                Err(_value) => error!("writing failed"),
-            });
-        self.file.write(b"\0\0\0\0")?;
+            .map(|(_, g)| collection_bytes(g))
+            .collect();
+        let names: Vec<&str> = collections.iter().map(|(name, _)| *name).collect();
+
+        let mut offset = table_size(&names) as u64;
+        let mut table = Vec::new();
+        table.extend_from_slice(&(collections.len() as u64).to_be_bytes());
+        for (name, body) in names.iter().zip(&bodies) {
+            table.extend_from_slice(&(name.len() as u64).to_be_bytes());
+            table.extend_from_slice(name.as_bytes());
+            table.extend_from_slice(&offset.to_be_bytes());
+            table.extend_from_slice(&(body.len() as u64).to_be_bytes());
+            offset += body.len() as u64;
+        }
+
+        self.file.write_all(MAGIC)?;
+        self.file.write_all(&(table.len() as u32).to_be_bytes())?;
+        self.file.write_all(&table)?;
+        for body in &bodies {
+            self.file.write_all(body)?;
+        }
         Ok(())
     }
+}
 
-    pub fn read(&mut self) -> Result<Box<Graph>, io::Error> {
-        let mut header_buffer = [0; 78];
-        self.file.read(&mut header_buffer)?;
-        let graph_bytes: [u8; 64] = header_buffer[14..78].try_into().unwrap();
-        let mut g = Graph::deserialize(&graph_bytes);
+impl<F: Read + Seek> GraphFile<F> {
+    // the collections in the file, older files have one called `DEFAULT_COLLECTION`
+    pub fn table(&mut self) -> Result<Vec<Entry>, io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 14];
+        self.file.read_exact(&mut magic)?;
+        if &magic == MAGIC_V1 || &magic == MAGIC_V0 {
+            let end = self.file.seek(SeekFrom::End(0))?;
+            return Ok(vec![Entry {
+                name: DEFAULT_COLLECTION.to_string(),
+                offset: magic.len() as u64,
+                size: end - magic.len() as u64,
+            }]);
+        }
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a vite file",
+            ));
+        }
 
         let mut size_buff = [0; 4];
-        let mut handle = self.file.try_clone().unwrap().take(4);
-        handle.read(&mut size_buff)?;
+        self.file.read_exact(&mut size_buff)?;
+        let table = self.read_bytes(u32::from_be_bytes(size_buff) as usize)?;
+
+        // the sizes in the table come from the file too, every read is checked against it
+        let take = |k: &mut usize, len: usize| -> io::Result<&[u8]> {
+            let bytes = k
+                .checked_add(len)
+                .and_then(|end| table.get(*k..end))
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "table of contents is cut short",
+                ))?;
+            *k += len;
+            Ok(bytes)
+        };
+        let read_u64 = |k: &mut usize| -> io::Result<u64> {
+            Ok(u64::from_be_bytes(take(k, 8)?.try_into().unwrap()))
+        };
+        let mut k = 0;
+        let mut entries = Vec::new();
+        for _i in 0..read_u64(&mut k)? {
+            let len = read_u64(&mut k)? as usize;
+            let name = String::from_utf8_lossy(take(&mut k, len)?).into_owned();
+            entries.push(Entry {
+                name,
+                offset: read_u64(&mut k)?,
+                size: read_u64(&mut k)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn read_collection(&mut self, name: &str) -> Result<Box<Graph>, io::Error> {
+        match self.table()?.iter().find(|entry| entry.name == name) {
+            Some(entry) => self.read_entry(entry),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no collection {}", name),
+            )),
+        }
+    }
+
+    pub fn read_all(&mut self) -> Result<Vec<(Entry, Box<Graph>)>, io::Error> {
+        self.table()?
+            .into_iter()
+            .map(|entry| {
+                let g = self.read_entry(&entry)?;
+                Ok((entry, g))
+            })
+            .collect()
+    }
+
+    fn read_entry(&mut self, entry: &Entry) -> Result<Box<Graph>, io::Error> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 14];
+        self.file.read_exact(&mut magic)?;
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        let graph_size = if &magic == MAGIC_V0 {
+            64
+        } else {
+            let mut size_buff = [0; 4];
+            self.file.read_exact(&mut size_buff)?;
+            u32::from_be_bytes(size_buff) as usize
+        };
+
+        let graph_bytes = self.read_bytes(graph_size)?;
+        let mut g = Graph::deserialize(&graph_bytes)?;
+
+        let mut size_buff = [0; 4];
+        self.file.read_exact(&mut size_buff)?;
 
         let mut node_size = u32::from_be_bytes(size_buff);
-        let mut buff: Vec<[u8; 1024]> = vec![];
-        let mut i = 0;
+        // nodes of a codes only graph were written without their vectors
+        let stored_dimension = if g.codes_only { 0 } else { g.dimension };
 
         while node_size > 0 {
-            buff.push([0; 1024]);
-            handle = self
-                .file
-                .try_clone()
-                .unwrap()
-                .take(node_size.try_into().unwrap());
-            let read = handle.read(&mut buff[i])?;
+            let buff = self.read_bytes(node_size as usize)?;
+            g.nodes
+                .push(Node::deserialize(&buff, stored_dimension, g.element_type)?);
+
+            self.file.read_exact(&mut size_buff)?;
+            node_size = u32::from_be_bytes(size_buff);
+        }
+        g.index_keys();
+        Ok(g)
+    }
+
+    // sizes come from the file, a damaged one runs out of bytes before it runs out of memory
+    fn read_bytes(&mut self, size: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        (&mut self.file).take(size as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file is cut short",
+            ));
+        }
+        Ok(bytes)
+    }
+}
 
-            node_size -= read as u32;
-            if node_size == 0 {
-                g.nodes.push(Node::deserialize(&buff.concat(), g.dimension));
+/*
+Replaces `collection` in the file at `path`, or adds it, and keeps the other
+collections. The new file is written next to the old one and renamed over it
+once it is on disk, so a crash halfway leaves the old file as it was.
+*/
+pub fn write_collection(path: &str, collection: &str, g: &Graph) -> io::Result<()> {
+    let others = if std::path::Path::new(path).exists() {
+        GraphFile::open(path.to_string())?.read_all()?
+    } else {
+        Vec::new()
+    };
 
-                handle = self.file.try_clone().unwrap().take(4);
-                handle.read(&mut size_buff)?;
-                node_size = u32::from_be_bytes(size_buff);
-                i = 0;
+    let mut collections: Vec<(&str, &Graph)> = others
+        .iter()
+        .map(|(entry, other)| {
+            if entry.name == collection {
+                (entry.name.as_str(), g)
             } else {
-                i += 1;
+                (entry.name.as_str(), other.as_ref())
             }
+        })
+        .collect();
+    if !collections.iter().any(|(name, _)| *name == collection) {
+        collections.push((collection, g));
+    }
+
+    let temp = format!("{}.tmp", path);
+    let written = GraphFile::create(temp.clone()).and_then(|mut file| {
+        file.write_all(&collections)?;
+        file.file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| std::fs::rename(&temp, path)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// magic, table of contents size and the table itself
+pub fn table_size(names: &[&str]) -> usize {
+    MAGIC.len()
+        + 4
+        + 8
+        + names
+            .iter()
+            .map(|name| 8 + name.len() + 8 + 8)
+            .sum::<usize>()
+}
+
+fn collection_bytes(g: &Graph) -> Vec<u8> {
+    let graph_bytes = g.serialize();
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(graph_bytes.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&graph_bytes);
+    g.nodes
+        .iter()
+        .for_each(|n| bytes.extend_from_slice(&n.borrow().serialize()));
+    bytes.extend_from_slice(b"\0\0\0\0");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::check::check;
+    use crate::hnsw::tests::{graph_bytes, random_graph, random_vector, seeded_graph};
+    use crate::hnsw::{knn_search, EntrencePoint, NodePtr};
+    use crate::metric::Metric;
+    use crate::payload::Payload;
+    use crate::quantize::{BinaryQuantizer, ProductQuantizer, Quantizer, ScalarQuantizer};
+    use crate::testing::temp_path;
+    use crate::vector::ElementType;
+
+    fn file_bytes(collections: &[(&str, &Graph)]) -> Vec<u8> {
+        let mut file = GraphFile { file: Vec::new() };
+        file.write_all(collections).unwrap();
+        file.file
+    }
+
+    #[test]
+    fn collections_are_kept_apart() {
+        let mut small = random_graph(2, 100, Metric::Cosine, ElementType::F64, 0);
+        let big = random_graph(16, 100, Metric::Euclidean, ElementType::F32, 1);
+        let path = temp_path("collections.vlite");
+        let path = path.to_str().unwrap();
+
+        GraphFile::create(path.to_string())
+            .unwrap()
+            .write_all(&[("small", &small), ("big", &big)])
+            .unwrap();
+        let mut file = GraphFile::open(path.to_string()).unwrap();
+        let names: Vec<String> = file.table().unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["small", "big"]);
+
+        // each collection reads back on its own with its own parameters
+        let read = file.read_collection("big").unwrap();
+        assert_eq!(read.dimension, 16);
+        assert_eq!(read.metric, Metric::Euclidean);
+        assert_eq!(read.nodes.len(), big.nodes.len());
+        assert_eq!(read.serialize(), big.serialize());
+        let read = file.read_collection("small").unwrap();
+        assert_eq!(read.nodes.len(), small.nodes.len());
+        assert!(file.read_collection("missing").is_err());
+
+        // replacing one collection keeps the other, and leaves no temporary file behind
+        small.insert(&[0.5, 0.5]);
+        write_collection(path, "small", &small).unwrap();
+        let all = GraphFile::open(path.to_string())
+            .unwrap()
+            .read_all()
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].1.nodes.len(), small.nodes.len());
+        assert_eq!(all[1].1.nodes.len(), big.nodes.len());
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn bytes_read_like_files() {
+        let mut g = random_graph(8, 100, Metric::Cosine, ElementType::F16, 0);
+        g.insert_with_key(&[0.5; 8], "half", false).unwrap();
+        let path = temp_path("from_bytes.vlite");
+        let path = path.to_str().unwrap().to_string();
+        GraphFile::create(path.clone())
+            .unwrap()
+            .write_all(&[("a", &g), ("b", &g)])
+            .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // bytes in memory read the same as the file they came from
+        let mut file = GraphFile::from_bytes(&bytes);
+        let names: Vec<String> = file.table().unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["a", "b"]);
+        let read = file.read_collection("b").unwrap();
+        assert_eq!(read.serialize(), g.serialize());
+        assert_eq!(read.id_of("half"), Some(100));
+        assert!(file.read_collection("c").is_err());
+        assert!(GraphFile::from_bytes(&bytes[..10]).table().is_err());
+    }
+
+    #[test]
+    fn seeds_are_kept() {
+        // building can carry on after a read as if the graph had never been written
+        let half = seeded_graph(42, 100);
+        let bytes = file_bytes(&[(DEFAULT_COLLECTION, &half)]);
+        let mut read = GraphFile::from_bytes(&bytes)
+            .read_collection(DEFAULT_COLLECTION)
+            .unwrap();
+        read.try_weaken_ep().unwrap();
+        read.try_link_friends().unwrap();
+        assert_eq!(read.seed, 42);
+        // `seeded_graph` draws its vectors from the same rng, the first 99 are already in
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let vectors: Vec<Vec<f64>> = (1..200).map(|_| random_vector(&mut rng, 4)).collect();
+        vectors[99..].iter().for_each(|v| {
+            read.insert(v);
+        });
+        assert_eq!(graph_bytes(&read), graph_bytes(&seeded_graph(42, 200)));
+
+        // the seed is found after quantizers of any size
+        let quantizers = [
+            Quantizer::Scalar(ScalarQuantizer::train(&half.vectors())),
+            Quantizer::Product(
+                ProductQuantizer::new(4, 2, 16)
+                    .unwrap()
+                    .train(&half.vectors(), 0),
+            ),
+            Quantizer::Binary(BinaryQuantizer { rerank: false }),
+        ];
+        for quantizer in quantizers {
+            let mut g = seeded_graph(7, 50);
+            g.quantize(Some(quantizer));
+            let bytes = file_bytes(&[(DEFAULT_COLLECTION, &g)]);
+            let read = GraphFile::from_bytes(&bytes)
+                .read_collection(DEFAULT_COLLECTION)
+                .unwrap();
+            assert_eq!(read.seed, 7);
         }
-        Ok(g)
+
+        // headers written before the seed was kept end at the codes only flag
+        let mut g = seeded_graph(7, 50);
+        g.quantize(Some(Quantizer::Scalar(ScalarQuantizer::train(
+            &g.vectors(),
+        ))));
+        g.drop_vectors().unwrap();
+        let header = g.serialize();
+        assert!(
+            Graph::deserialize(&header[..header.len() - 8])
+                .unwrap()
+                .codes_only
+        );
+    }
+
+    #[test]
+    fn damaged_tables_are_errors() {
+        let g = random_graph(2, 5, Metric::Cosine, ElementType::F64, 0);
+        let bytes = file_bytes(&[("a", &g), ("b", &g)]);
+        let table = table_size(&["a", "b"]);
+        for at in MAGIC.len()..table {
+            for bit in 0..8 {
+                let mut damaged = bytes.clone();
+                damaged[at] ^= 1 << bit;
+                // any error is fine, and whatever does read is a graph that was written
+                // or one that check turns away
+                if let Ok(collections) = GraphFile::from_bytes(&damaged).read_all() {
+                    assert!(
+                        collections.iter().all(|(_, read)| {
+                            graph_bytes(read) == graph_bytes(&g) || !check(read).is_empty()
+                        }),
+                        "bit {} of byte {}",
+                        bit,
+                        at
+                    );
+                }
+            }
+        }
+        for len in 0..table {
+            assert!(GraphFile::from_bytes(&bytes[..len]).table().is_err());
+        }
+    }
+
+    #[test]
+    fn keys_and_deletes_are_kept() {
+        let mut g = random_graph(2, 10, Metric::Euclidean, ElementType::F64, 0);
+        for i in 0..10 {
+            g.insert_with_key(&[i as f64, 0.5], &format!("k{}", i), false)
+                .unwrap();
+        }
+        let old = g.id_of("k3").unwrap();
+        g.insert_with_key(&[0.5, 0.5], "k3", true).unwrap();
+        g.delete(g.id_of("k7").unwrap());
+
+        let bytes = file_bytes(&[(DEFAULT_COLLECTION, &g)]);
+        let read = GraphFile::from_bytes(&bytes)
+            .read_collection(DEFAULT_COLLECTION)
+            .unwrap();
+        assert_eq!(read.keys, g.keys);
+        assert!(read.nodes[old].borrow().deleted);
+        assert_eq!(read.id_of("k7"), None);
+    }
+
+    #[test]
+    fn codes_only_graphs_are_kept() {
+        let mut g = random_graph(16, 200, Metric::Euclidean, ElementType::F32, 0);
+        g.quantize(Some(Quantizer::Scalar(ScalarQuantizer::train(
+            &g.vectors(),
+        ))));
+        let with_vectors = file_bytes(&[(DEFAULT_COLLECTION, &g)]).len();
+        g.drop_vectors().unwrap();
+
+        // the file loses the vectors too, and reads back to the same graph
+        let bytes = file_bytes(&[(DEFAULT_COLLECTION, &g)]);
+        assert_eq!(bytes.len(), with_vectors - 200 * 16 * 4);
+        let mut read = GraphFile::from_bytes(&bytes)
+            .read_collection(DEFAULT_COLLECTION)
+            .unwrap();
+        read.try_weaken_ep().unwrap();
+        read.try_link_friends().unwrap();
+        assert!(read.codes_only);
+        assert_eq!(graph_bytes(&read), graph_bytes(&g));
+        let q = [0.5; 16];
+        let ids =
+            |g: &Graph| -> Vec<usize> { knn_search(g, &q, 10, 20).iter().map(|r| r.id).collect() };
+        assert_eq!(ids(&read), ids(&g));
+
+        // a node without codes in a codes only graph can't be searched
+        g.nodes[5].borrow_mut().codes = Box::new([]);
+        let bytes = file_bytes(&[(DEFAULT_COLLECTION, &g)]);
+        let mut read = GraphFile::from_bytes(&bytes)
+            .read_collection(DEFAULT_COLLECTION)
+            .unwrap();
+        read.try_weaken_ep().unwrap();
+        let error = read.try_link_friends().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn damaged_collections_are_errors() {
+        let mut g = random_graph(4, 20, Metric::Cosine, ElementType::F32, 1);
+        g.insert_with_key(&[0.5; 4], "half", false).unwrap();
+        let json = serde_json::json!({"title": "a", "year": 1965, "tags": ["x"]});
+        g.nodes[3].borrow_mut().payload = Payload::from_json(&json).unwrap();
+        let bytes = file_bytes(&[(DEFAULT_COLLECTION, &g)]);
+        let read = |bytes: &[u8]| GraphFile::from_bytes(bytes).read_collection(DEFAULT_COLLECTION);
+        assert!(read(&bytes).is_ok());
+
+        // a file cut short anywhere fails to read
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err(), "cut at {}", len);
+        }
+
+        // a flipped bit either reads as a graph check can look at, or fails,
+        // and a graph that links can be searched
+        let start = table_size(&[DEFAULT_COLLECTION]);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _i in 0..2000 {
+            let mut damaged = bytes.clone();
+            let at = rng.gen_range(start..damaged.len());
+            damaged[at] ^= 1 << rng.gen_range(0..8);
+            if let Ok(mut g) = read(&damaged) {
+                check(&g);
+                if g.try_weaken_ep()
+                    .and_then(|()| g.try_link_friends())
+                    .is_ok()
+                {
+                    knn_search(&g, &[0.5; 4], 5, 20);
+                }
+            }
+        }
+
+        // friends, entrence points and codes that would send a search out of bounds are refused
+        let link = |g: &mut Graph| g.try_weaken_ep().and_then(|()| g.try_link_friends());
+        let friend = read(&bytes).unwrap();
+        friend.nodes[2].borrow_mut().friend_layers[0].push(NodePtr::Index(1000));
+        let mut entrence_point = read(&bytes).unwrap();
+        entrence_point.entrence_point = EntrencePoint::Index(1000);
+        let mut empty = read(&bytes).unwrap();
+        empty.nodes.clear();
+        let mut codes = read(&bytes).unwrap();
+        codes.quantizer = Some(Quantizer::Binary(BinaryQuantizer { rerank: true }));
+        codes.nodes[4].borrow_mut().codes = vec![0; 3].into();
+        for mut g in [friend, entrence_point, empty, codes] {
+            assert_eq!(link(&mut g).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn norms_are_kept() {
+        let g = random_graph(8, 50, Metric::Cosine, ElementType::F32, 0);
+        let bytes = file_bytes(&[(DEFAULT_COLLECTION, &g)]);
+        let read = GraphFile::from_bytes(&bytes)
+            .read_collection(DEFAULT_COLLECTION)
+            .unwrap();
+        for (node, read) in g.nodes.iter().zip(&read.nodes) {
+            assert_eq!(read.borrow().norm, node.borrow().norm);
+            assert_eq!(read.borrow().norm, read.borrow().vector.norm());
+        }
+
+        // nodes written before norms were cached work theirs out, they end
+        // before the norm, the payload and the flags
+        let node = g.nodes[7].borrow();
+        let bytes = node.serialize();
+        let end = bytes.len() - 8 - node.payload.serialized_size() - 1;
+        let old = Node::deserialize(&bytes[4..end], 8, ElementType::F32).unwrap();
+        assert_eq!(old.borrow().norm, node.norm);
     }
 }
@@ -0,0 +1,72 @@
+/*
+Which node ids a filtered search is allowed to return. Any `Fn(usize) -> bool`
+is a filter, `IdSet` is a bitset for when the allowed ids are known up front.
+*/
+pub trait Filter {
+    fn allows(&self, id: usize) -> bool;
+
+    // how many ids are allowed, if that is known without asking about each one
+    fn allowed_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<F: Fn(usize) -> bool> Filter for F {
+    fn allows(&self, id: usize) -> bool {
+        self(id)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct IdSet {
+    bits: Vec<u64>,
+}
+
+impl IdSet {
+    pub fn new() -> Self {
+        IdSet { bits: Vec::new() }
+    }
+
+    pub fn insert(&mut self, id: usize) {
+        let word = id / 64;
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << (id % 64);
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&word| word == 0)
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.bits
+            .get(id / 64)
+            .is_some_and(|word| word & (1 << (id % 64)) != 0)
+    }
+}
+
+impl FromIterator<usize> for IdSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = IdSet::new();
+        iter.into_iter().for_each(|id| set.insert(id));
+        set
+    }
+}
+
+impl Filter for IdSet {
+    fn allows(&self, id: usize) -> bool {
+        self.contains(id)
+    }
+
+    fn allowed_count(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
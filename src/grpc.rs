@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::server::Server;
+use crate::{insert_node, read_graph, search_graph, NodeOptions, SearchOptions};
+use vite::condition::Condition;
+use vite::hnsw::{Budget, Graph};
+use vite::output::Format;
+use vite::payload::{Payload, Value};
+
+pub mod proto {
+    tonic::include_proto!("vite");
+}
+use proto::vite_server::{Vite, ViteServer};
+
+/*
+The gRPC service from proto/vite.proto over one collection. The graph isn't
+Send, so it lives on its own thread and every request is a job run there in
+the order it arrived, the same one at a time the HTTP server has.
+*/
+type Job = Box<dyn FnOnce(&mut Server) + Send>;
+
+pub struct Service {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+// a node picked by id or by key
+enum Selector {
+    Id(u64),
+    Key(String),
+}
+
+impl Service {
+    // starts the graph thread, fails if the collection can't be read
+    pub fn new(filename: &str, collection: &str) -> Result<Self, String> {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        let (loaded, ready) = std::sync::mpsc::channel();
+        let (filename, collection) = (filename.to_string(), collection.to_string());
+        thread::spawn(move || {
+            let mut server =
+                Server::new(&filename, &collection, read_graph(&filename, &collection));
+            loaded.send(()).unwrap();
+            while let Some(job) = queue.blocking_recv() {
+                job(&mut server);
+            }
+        });
+        ready
+            .recv()
+            .map_err(|_| "could not read graph".to_string())?;
+        Ok(Service { jobs })
+    }
+
+    async fn run<T, F>(&self, job: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Server) -> Result<T, Status> + Send + 'static,
+    {
+        let (reply, answer) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |server| {
+                let _ = reply.send(job(server));
+            }))
+            .map_err(|_| Status::unavailable("graph thread stopped"))?;
+        answer
+            .await
+            .map_err(|_| Status::internal("graph thread stopped"))?
+    }
+}
+
+pub fn serve(filename: &str, collection: &str, address: &str) -> Result<(), String> {
+    let address = address.parse().map_err(|e| format!("{}: {}", address, e))?;
+    let service = Service::new(filename, collection)?;
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(ViteServer::new(service))
+                .serve(address),
+        )
+        .map_err(|e| e.to_string())
+}
+
+#[tonic::async_trait]
+impl Vite for Service {
+    async fn search(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<proto::SearchResponse>, Status> {
+        let request = request.into_inner();
+        let k = if request.k == 0 {
+            10
+        } else {
+            request.k as usize
+        };
+        let condition = match &request.r#where {
+            Some(condition) => Some(Condition::parse(condition).map_err(Status::invalid_argument)?),
+            None => None,
+        };
+        let options = SearchOptions {
+            format: Format::Json,
+            vectors: request.vectors,
+            similarity: request.similarity,
+            exact: request.exact,
+            ids: (!request.ids.is_empty())
+                .then(|| request.ids.iter().map(|&id| id as usize).collect()),
+            condition,
+            budget: Budget {
+                distances: request.max_distances.map(|n| n as usize),
+                visited: request.max_visited.map(|n| n as usize),
+                time: request.timeout_ms.map(Duration::from_millis),
+            },
+        };
+
+        self.run(move |server| {
+            let g = &server.g;
+            check_dimension(g, &request.vector)?;
+            let search = search_graph(g, &request.vector, k, &options);
+            let results = search
+                .results
+                .into_iter()
+                .map(|r| {
+                    let n = g.nodes[r.id].borrow();
+                    proto::SearchResult {
+                        id: r.id as u64,
+                        distance: r.distance,
+                        similarity: options.similarity.then(|| r.similarity(g.metric)),
+                        vector: if options.vectors {
+                            g.vector(r.id)
+                        } else {
+                            Vec::new()
+                        },
+                        key: n.key.clone(),
+                        payload: payload_to_proto(&n.payload),
+                    }
+                })
+                .collect();
+            Ok(Response::new(proto::SearchResponse {
+                results,
+                partial: search.partial,
+            }))
+        })
+        .await
+    }
+
+    async fn insert(
+        &self,
+        request: Request<proto::InsertRequest>,
+    ) -> Result<Response<proto::InsertResponse>, Status> {
+        let (vector, node) = node_options(request.into_inner())?;
+        self.run(move |server| {
+            let inserted = insert(&mut server.g, &vector, node)?;
+            save_inserts(server, &[inserted])?;
+            Ok(Response::new(proto::InsertResponse {
+                id: inserted.0 as u64,
+            }))
+        })
+        .await
+    }
+
+    async fn insert_batch(
+        &self,
+        request: Request<Streaming<proto::InsertRequest>>,
+    ) -> Result<Response<proto::InsertBatchResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut batch = Vec::new();
+        while let Some(request) = stream.message().await? {
+            batch.push(node_options(request)?);
+        }
+
+        // stops at the first failure, what was inserted before it is kept
+        // unless the file can't be written, then the whole batch is taken back
+        self.run(move |server| {
+            let mut inserted = Vec::new();
+            let mut failure = None;
+            for (i, (vector, node)) in batch.into_iter().enumerate() {
+                match insert(&mut server.g, &vector, node) {
+                    Ok(node) => inserted.push(node),
+                    Err(status) => {
+                        failure = Some(Status::new(
+                            status.code(),
+                            format!("vector {}: {}", i, status.message()),
+                        ));
+                        break;
+                    }
+                }
+            }
+            if !inserted.is_empty() {
+                save_inserts(server, &inserted)?;
+            }
+            match failure {
+                Some(status) => Err(status),
+                None => Ok(Response::new(proto::InsertBatchResponse {
+                    ids: inserted.iter().map(|&(id, _)| id as u64).collect(),
+                })),
+            }
+        })
+        .await
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::Node>, Status> {
+        let selector = match request.into_inner().node {
+            Some(proto::get_request::Node::Id(id)) => Selector::Id(id),
+            Some(proto::get_request::Node::Key(key)) => Selector::Key(key),
+            None => return Err(Status::invalid_argument("give an id or a key")),
+        };
+        self.run(move |server| {
+            let g = &server.g;
+            let n = g.nodes[find(g, &selector)?].borrow();
+            Ok(Response::new(proto::Node {
+                id: n.index as u64,
+                level: n.max_level as u64,
+                vector: g.vector(n.index),
+                key: n.key.clone(),
+                payload: payload_to_proto(&n.payload),
+            }))
+        })
+        .await
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<proto::DeleteResponse>, Status> {
+        let selector = match request.into_inner().node {
+            Some(proto::delete_request::Node::Id(id)) => Selector::Id(id),
+            Some(proto::delete_request::Node::Key(key)) => Selector::Key(key),
+            None => return Err(Status::invalid_argument("give an id or a key")),
+        };
+        self.run(move |server| {
+            let id = find(&server.g, &selector)?;
+            if server.g.delete(id) {
+                save(server).inspect_err(|_| server.undelete(id))?;
+            }
+            Ok(Response::new(proto::DeleteResponse { id: id as u64 }))
+        })
+        .await
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<proto::StatsRequest>,
+    ) -> Result<Response<proto::StatsResponse>, Status> {
+        self.run(|server| {
+            let stats = server.graph_stats();
+            Ok(Response::new(proto::StatsResponse {
+                nodes: stats.nodes as u64,
+                dimension: stats.dimension as u64,
+                deleted: stats.deleted as u64,
+                unreachable: stats.unreachable as u64,
+                metric: stats.metric.name().to_string(),
+                element_type: stats.element_type.name().to_string(),
+                quantizer: stats.quantizer.to_string(),
+                layer_count: stats.layer_count as u64,
+                file_bytes: stats.file_bytes.unwrap_or(0),
+            }))
+        })
+        .await
+    }
+}
+
+fn save(server: &Server) -> Result<(), Status> {
+    server
+        .save()
+        .map_err(|(_, message)| Status::internal(message))
+}
+
+fn save_inserts(server: &mut Server, inserted: &[(usize, Option<usize>)]) -> Result<(), Status> {
+    server
+        .save_inserts(inserted)
+        .map_err(|(_, message)| Status::internal(message))
+}
+
+fn check_dimension(g: &Graph, vector: &[f64]) -> Result<(), Status> {
+    if vector.len() != g.dimension {
+        return Err(Status::invalid_argument(format!(
+            "expected a vector of length {}",
+            g.dimension
+        )));
+    }
+    Ok(())
+}
+
+// the new node's id and the node an upsert moved its key away from
+fn insert(
+    g: &mut Graph,
+    vector: &[f64],
+    node: NodeOptions,
+) -> Result<(usize, Option<usize>), Status> {
+    check_dimension(g, vector)?;
+    let key = node.key.clone();
+    let replaced = key.as_ref().and_then(|key| g.id_of(key));
+    let id = insert_node(g, vector, node).map_err(|existing| {
+        Status::already_exists(format!(
+            "key {} is already node {}, set upsert to replace it",
+            key.unwrap_or_default(),
+            existing
+        ))
+    })?;
+    Ok((id, replaced))
+}
+
+// deleted nodes are never found
+fn find(g: &Graph, selector: &Selector) -> Result<usize, Status> {
+    let id = match selector {
+        Selector::Id(id) => {
+            Some(*id as usize).filter(|&id| id < g.nodes.len() && !g.nodes[id].borrow().deleted)
+        }
+        Selector::Key(key) => g.id_of(key),
+    };
+    id.ok_or_else(|| Status::not_found("no such node"))
+}
+
+fn node_options(request: proto::InsertRequest) -> Result<(Vec<f64>, NodeOptions), Status> {
+    let node = NodeOptions {
+        payload: payload_from_proto(request.payload)?,
+        key: request.key,
+        upsert: request.upsert,
+    };
+    Ok((request.vector, node))
+}
+
+fn payload_from_proto(payload: HashMap<String, proto::Value>) -> Result<Payload, Status> {
+    let mut fields = std::collections::BTreeMap::new();
+    for (key, value) in payload {
+        let value = match value.kind {
+            Some(proto::value::Kind::String(s)) => Value::String(s),
+            Some(proto::value::Kind::Number(n)) => Value::Number(n),
+            Some(proto::value::Kind::Bool(b)) => Value::Bool(b),
+            Some(proto::value::Kind::Tags(tags)) => Value::Tags(tags.tags),
+            None => return Err(Status::invalid_argument(format!("{}: no value", key))),
+        };
+        fields.insert(key, value);
+    }
+    Ok(Payload { fields })
+}
+
+fn payload_to_proto(payload: &Payload) -> HashMap<String, proto::Value> {
+    payload
+        .fields
+        .iter()
+        .map(|(key, value)| {
+            let kind = match value {
+                Value::String(s) => proto::value::Kind::String(s.clone()),
+                Value::Number(n) => proto::value::Kind::Number(*n),
+                Value::Bool(b) => proto::value::Kind::Bool(*b),
+                Value::Tags(tags) => proto::value::Kind::Tags(proto::Tags { tags: tags.clone() }),
+            };
+            (key.clone(), proto::Value { kind: Some(kind) })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use tonic::codegen::tokio_stream;
+
+    use super::*;
+    use crate::testing::{connect, reopen, small_graph, temp_path};
+    use proto::vite_client::ViteClient;
+    use vite::file::{write_collection, DEFAULT_COLLECTION};
+
+    #[test]
+    fn service() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        // in a directory of its own, taking that away makes every save fail
+        let dir = temp_path("grpc");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("graph.vlite").to_str().unwrap().to_string();
+        write_collection(&path, DEFAULT_COLLECTION, &small_graph()).unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let filename = path.clone();
+        thread::spawn(move || {
+            serve(
+                &filename,
+                DEFAULT_COLLECTION,
+                &format!("127.0.0.1:{}", port),
+            )
+            .unwrap()
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let url = format!("http://127.0.0.1:{}", port);
+        let mut client = connect(|| runtime.block_on(ViteClient::connect(url.clone())));
+        runtime.block_on(async {
+            let tags = proto::Value {
+                kind: Some(proto::value::Kind::Tags(proto::Tags {
+                    tags: vec!["x".into()],
+                })),
+            };
+            let insert = |vector: Vec<f64>, key: &str| proto::InsertRequest {
+                vector,
+                key: Some(key.to_string()),
+                payload: [("t".to_string(), tags.clone())].into(),
+                upsert: false,
+            };
+            let id = client.insert(insert(vec![0.0, 1.0], "a")).await.unwrap();
+            assert_eq!(id.into_inner().id, 1);
+            let conflict = client.insert(insert(vec![0.0, 1.0], "a")).await;
+            assert_eq!(conflict.unwrap_err().code(), tonic::Code::AlreadyExists);
+
+            let vectors: Vec<Vec<f64>> = (0..20).map(|_| vec![rng.gen(), rng.gen()]).collect();
+            let batch: Vec<proto::InsertRequest> = vectors
+                .iter()
+                .enumerate()
+                .map(|(i, vector)| insert(vector.clone(), &format!("b{}", i)))
+                .collect();
+            let ids = client
+                .insert_batch(tokio_stream::iter(batch))
+                .await
+                .unwrap()
+                .into_inner()
+                .ids;
+            assert_eq!(ids, (2..22).collect::<Vec<u64>>());
+
+            let search = client
+                .search(proto::SearchRequest {
+                    vector: vec![0.0, 1.0],
+                    k: 1,
+                    r#where: Some(r#"t CONTAINS "x""#.into()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(search.results[0].key.as_deref(), Some("a"));
+            assert_eq!(search.results[0].payload["t"], tags);
+            assert!(!search.partial);
+            let search = client
+                .search(proto::SearchRequest {
+                    vector: vec![0.0, 1.0],
+                    timeout_ms: Some(0),
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(search.partial);
+            let bad = client
+                .search(proto::SearchRequest {
+                    vector: vec![1.0],
+                    ..Default::default()
+                })
+                .await;
+            assert_eq!(bad.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+            let key = |key: &str| Some(proto::get_request::Node::Key(key.into()));
+            let node = client.get(proto::GetRequest { node: key("b3") }).await;
+            assert_eq!(node.unwrap().into_inner().vector, vectors[3]);
+            client
+                .delete(proto::DeleteRequest {
+                    node: Some(proto::delete_request::Node::Id(0)),
+                })
+                .await
+                .unwrap();
+            let gone = client
+                .get(proto::GetRequest {
+                    node: Some(proto::get_request::Node::Id(0)),
+                })
+                .await;
+            assert_eq!(gone.unwrap_err().code(), tonic::Code::NotFound);
+
+            let stats = client.stats(proto::StatsRequest {}).await.unwrap();
+            assert_eq!((stats.get_ref().nodes, stats.get_ref().deleted), (22, 1));
+
+            let g = reopen(&path);
+            assert_eq!(g.nodes.len(), 22);
+            assert!(g.nodes[0].borrow().deleted);
+
+            // writes that can't be saved are taken back
+            std::fs::remove_dir_all(&dir).unwrap();
+            let failed = client.insert(insert(vec![1.0, 1.0], "c")).await;
+            assert_eq!(failed.unwrap_err().code(), tonic::Code::Internal);
+            let upsert = proto::InsertRequest {
+                upsert: true,
+                ..insert(vec![1.0, 1.0], "b3")
+            };
+            let batch = vec![insert(vec![1.0, 1.0], "d"), upsert];
+            let failed = client.insert_batch(tokio_stream::iter(batch)).await;
+            assert_eq!(failed.unwrap_err().code(), tonic::Code::Internal);
+            let failed = client.delete(proto::DeleteRequest {
+                node: Some(proto::delete_request::Node::Key("b4".into())),
+            });
+            assert_eq!(failed.await.unwrap_err().code(), tonic::Code::Internal);
+            for missing in ["c", "d"] {
+                let node = client.get(proto::GetRequest { node: key(missing) }).await;
+                assert_eq!(node.unwrap_err().code(), tonic::Code::NotFound);
+            }
+            let node = client.get(proto::GetRequest { node: key("b3") }).await;
+            assert_eq!(node.unwrap().into_inner().vector, vectors[3]);
+            let node = client.get(proto::GetRequest { node: key("b4") }).await;
+            assert_eq!(node.unwrap().into_inner().vector, vectors[4]);
+            let stats = client.stats(proto::StatsRequest {}).await.unwrap();
+            assert_eq!((stats.get_ref().nodes, stats.get_ref().deleted), (25, 4));
+        });
+    }
+}
@@ -2,15 +2,24 @@
 #[allow(unused_variables)]
 use core::cmp::{Ordering, Reverse};
 use rand::distributions::Uniform;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use std::{
     cell::RefCell,
     cmp::min,
-    collections::BinaryHeap,
-    io::Write,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::{self, Write},
     rc::{Rc, Weak},
+    time::{Duration, Instant},
 };
 
+use crate::check::check;
+use crate::filter::Filter;
+use crate::metric::Metric;
+use crate::payload::Payload;
+use crate::quantize::{PreparedQuery, Quantizer};
+use crate::vector::{ElementType, Vector};
+
 pub enum EntrencePoint {
     Weak(NodeWeak),
     Index(usize),
@@ -32,6 +41,14 @@ impl EntrencePoint {
             None
         }
     }
+
+    // node index of the entrence point, whichever way it is stored
+    pub fn id(&self) -> Option<usize> {
+        match self.weak() {
+            Some(weak) => weak.upgrade().map(|n| n.borrow().index),
+            None => self.index().copied(),
+        }
+    }
 }
 
 pub struct Graph {
@@ -51,131 +68,258 @@ pub struct Graph {
     pub candidate_list_size: usize,
     // length of vectors
     pub dimension: usize,
+    // how distances between vectors are measured
+    pub metric: Metric,
+    // how vector components are stored
+    pub element_type: ElementType,
+    // when set, searches walk the graph using each node's codes
+    pub quantizer: Option<Quantizer>,
+    // nodes keep only their codes, the full vectors were dropped to save memory
+    pub codes_only: bool,
+    // external key -> node id, for every live node that has a key
+    pub keys: HashMap<String, usize>,
+    // node levels and pq codebooks are drawn from this, the same inserts build the same graph
+    pub seed: u64,
+}
+
+// a node found by `knn_search` and how far it is from the query
+#[derive(Clone, Copy, Debug)]
+pub struct SearchResult {
+    pub id: usize,
+    pub distance: f64,
+}
+
+impl SearchResult {
+    pub fn similarity(&self, metric: Metric) -> f64 {
+        metric.similarity(self.distance)
+    }
+}
+
+/*
+Limits on one search, whichever runs out first ends it with the best results
+found so far. `distances` counts vectors compared against the query, `visited`
+the nodes whose friends were looked at, on every layer. The default has no limits.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub distances: Option<usize>,
+    pub visited: Option<usize>,
+    pub time: Option<Duration>,
+}
+
+// what `knn_search_budgeted` found, `partial` when the budget ran out first
+#[derive(Debug)]
+pub struct BudgetedSearch {
+    pub results: Vec<SearchResult>,
+    pub partial: bool,
+}
+
+// how much of its budget a search has used, once anything runs out it stays out
+struct Spent {
+    budget: Budget,
+    // only taken with a time limit, wasm has no clock
+    start: Option<Instant>,
+    distances: usize,
+    visited: usize,
+    exhausted: bool,
+}
+
+impl Spent {
+    fn new(budget: Budget) -> Self {
+        Spent {
+            budget,
+            start: budget.time.map(|_| Instant::now()),
+            distances: 0,
+            visited: 0,
+            exhausted: false,
+        }
+    }
+
+    fn unlimited() -> Self {
+        Spent::new(Budget::default())
+    }
+
+    // false when there's no budget left to look at another node's friends
+    fn visit(&mut self) -> bool {
+        self.visited += 1;
+        let late = self
+            .start
+            .zip(self.budget.time)
+            .is_some_and(|(start, time)| start.elapsed() >= time);
+        if late || self.budget.visited.is_some_and(|max| self.visited > max) {
+            self.exhausted = true;
+        }
+        !self.exhausted
+    }
+
+    // false when there's no budget left to compare another vector
+    fn measure(&mut self) -> bool {
+        self.distances += 1;
+        if self
+            .budget
+            .distances
+            .is_some_and(|max| self.distances > max)
+        {
+            self.exhausted = true;
+        }
+        !self.exhausted
+    }
 }
 
 impl Graph {
     /*
     q: the vector
     m: "target number of established connections"? a reasonable range is between 5 and 48. This parameter is proportional to memory consumption
+    metric: distance function used for building and searching, fixed for the life of the graph
+    element_type: how vectors are stored in memory and on disk, also fixed
+    seed: makes construction reproducible, kept in the file
+    an m of 1 or less would put every node on infinitely many layers, so it is refused
     */
-    pub fn new(q: &[f64], m: f64, m_max: usize, m_max0: usize, candidate_list_size: usize) -> Self {
-        let node = Node::new(0, 0, q);
-        Graph {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        q: &[f64],
+        m: f64,
+        m_max: usize,
+        m_max0: usize,
+        candidate_list_size: usize,
+        metric: Metric,
+        element_type: ElementType,
+        seed: u64,
+    ) -> Result<Self, String> {
+        if m.is_nan() || m <= 1.0 {
+            return Err(format!("m must be greater than 1, not {}", m));
+        }
+        let m_l = 1.0 / m.ln();
+        // the first node is the entrence point, whatever level it gets
+        let level = calc_level(m_l, seed, 0);
+        let node = Node::new(0, level, Vector::encode(element_type, q));
+        Ok(Graph {
             nodes: vec![node.clone()],
             entrence_point: EntrencePoint::Weak(Rc::downgrade(&node)),
-            layer_count: 1,
-            m_l: 1.0 / m.ln(),
-            m_max: m_max,
-            m_max0: m_max0,
-            candidate_list_size: candidate_list_size,
+            layer_count: level + 1,
+            m_l,
+            m_max,
+            m_max0,
+            candidate_list_size,
             dimension: q.len(),
-        }
+            metric,
+            element_type,
+            quantizer: None,
+            codes_only: false,
+            keys: HashMap::new(),
+            seed,
+        })
     }
 
-    pub fn insert(&mut self, q: &[f64]) {
-        let new_level = min(calc_level(self.m_l), self.layer_count);
-
-        self.nodes.push(Node::new(self.nodes.len(), new_level, q));
+    /*
+    Insertion as in the HNSW paper: the node gets a random level, a greedy walk
+    with one candidate goes from the top layer down to the layer above that
+    level, then the node is linked on every layer from min(top, level) to 0.
+    A level above the top makes the node the new entrence point.
+    returns the id of the new node
+    */
+    pub fn insert(&mut self, q: &[f64]) -> usize {
+        let level = calc_level(self.m_l, self.seed, self.nodes.len());
 
-        let mut push_ep = false;
-        let old_ep = self
-            .entrence_point
-            .weak()
-            .unwrap()
-            .clone()
-            .upgrade()
-            .unwrap();
-        if new_level >= self.layer_count {
-            push_ep = true;
-            self.layer_count += 1;
-            self.entrence_point =
-                EntrencePoint::Weak(Rc::downgrade(&self.nodes.last().unwrap().clone()));
+        let node = Node::new(
+            self.nodes.len(),
+            level,
+            Vector::encode(self.element_type, q),
+        );
+        if let Some(quantizer) = &self.quantizer {
+            node.borrow_mut().codes = quantizer.encode(q);
         }
-
-        for _i in 0..new_level {
-            let mut new_node = self.nodes.last().unwrap().borrow_mut();
-            new_node.friend_layers.push(Vec::new());
+        if self.codes_only {
+            node.borrow_mut().vector = Vector::encode(self.element_type, &[]);
         }
+        self.nodes.push(node.clone());
+        // without vectors the new node is placed by its distance to the other nodes' codes
+        let codes_only = self.codes_only.then_some(&*self);
+        let query = match codes_only {
+            Some(g) => Query::new(g, q),
+            None => Query::of_node(&node.borrow(), self),
+        };
 
-        let mut ep = self
-            .entrence_point
-            .weak()
-            .unwrap()
-            .clone()
-            .upgrade()
-            .unwrap();
-        let ep_level = ep.borrow().max_level;
-        for i in ep_level..=new_level {
-            ep = if let Some(ep) = search_layer(&q, ep.clone(), 1, i).pop() {
-                ep.ptr().unwrap().clone()
-            } else {
-                ep
+        let mut ep = self.entrence_point.weak().unwrap().upgrade().unwrap();
+        let top = ep.borrow().max_level;
+        let spent = &mut Spent::unlimited();
+
+        // greedy descent, the nearest node on each layer is where the next one starts
+        for layer in (level + 1..=top).rev() {
+            if let Some(nearest) = search_layer(&query, ep.clone(), 1, layer, None, spent).first() {
+                ep = nearest.node.clone();
             }
         }
 
-        for i in (0..=min(self.layer_count - 1, new_level)).rev() {
+        for layer in (0..=min(top, level)).rev() {
             // for each layer we need to fill in the neighbors of new_node
-            let nearest_nodes = search_layer(&q, ep.clone(), self.candidate_list_size, i); // hmmm
-            let m = if i > 0 { self.m_max } else { self.m_max0 };
-
-            let neighbors = select_neighbors_simple(&q, &nearest_nodes, m);
+            let nearest_nodes: Vec<NodePtr> = search_layer(
+                &query,
+                ep.clone(),
+                self.candidate_list_size,
+                layer,
+                None,
+                spent,
+            )
+            .into_iter()
+            .map(|a| NodePtr::Ptr(a.node))
+            .collect();
+            let m = if layer > 0 { self.m_max } else { self.m_max0 };
+
+            let neighbors = select_neighbors(&query, &nearest_nodes, m, self);
 
             // fill friends
             for v in &neighbors {
-                let new_node = self.nodes.last().unwrap();
-                if !v.ptr().unwrap().eq(new_node) {
-                    push_friend(
-                        new_node.clone(),
-                        v.ptr().unwrap().clone(),
-                        i,
-                        self.m_max,
-                        self.m_max0,
-                        true,
-                    );
+                if !v.ptr().unwrap().eq(&node) {
+                    push_friend(node.clone(), v.ptr().unwrap().clone(), layer, self, true);
                 }
             }
-            if push_ep {
-                ep = old_ep.clone();
-                push_ep = false;
-            } else {
-                ep = nearest_nodes[0].ptr().unwrap().clone();
+            if let Some(nearest) = nearest_nodes.first() {
+                ep = nearest.ptr().unwrap().clone();
+            }
+        }
+
+        if level > top {
+            self.layer_count = level + 1;
+            self.entrence_point = EntrencePoint::Weak(Rc::downgrade(&node));
+        }
+
+        // product quantizers wait until there are enough vectors to train their codebooks,
+        // and retrain them as the graph grows past what they were trained on
+        // codes-only graphs keep the codebooks they have, there are no vectors to train on
+        if let Some(Quantizer::Product(pq)) = &self.quantizer {
+            if !self.codes_only && pq.needs_training(self.nodes.len()) {
+                let trained = pq.train(&self.vectors(), self.seed);
+                self.quantize(Some(Quantizer::Product(trained)));
             }
         }
+
+        self.nodes.len() - 1
     }
 
-    // will always be 64 bytes according to file.rs
-    pub fn serialize(&self) -> Box<[u8; 64]> {
+    // the first 64 bytes are the format 0 header, newer fields are appended after it
+    pub fn serialize(&self) -> Box<[u8]> {
         let mut collect: Vec<u8> = Vec::new();
         // vector dimension
-        self.nodes[0]
-            .borrow()
-            .vector
-            .len()
+        (self.dimension as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
 
         // node count
-        self.nodes
-            .len()
+        (self.nodes.len() as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
 
         // entrence point index
-        self.entrence_point
-            .weak()
-            .unwrap()
-            .upgrade()
-            .unwrap()
-            .borrow()
-            .index
+        (self.entrence_point.id().unwrap() as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
 
         // layers
-        self.layer_count
+        (self.layer_count as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
@@ -184,27 +328,52 @@ impl Graph {
         self.m_l.to_be_bytes().iter().for_each(|&x| collect.push(x));
 
         // m_max
-        self.m_max
+        (self.m_max as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
 
         // m_max0
-        self.m_max0
+        (self.m_max0 as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
 
         // candidate list length
-        self.candidate_list_size
+        (self.candidate_list_size as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
 
-        Box::new(collect.try_into().unwrap())
+        // metric
+        collect.push(self.metric.to_byte());
+
+        // element type
+        collect.push(self.element_type.to_byte());
+
+        // quantizer
+        match &self.quantizer {
+            Some(quantizer) => quantizer.serialize(&mut collect),
+            None => collect.push(0),
+        }
+
+        // whether nodes were written without their vectors
+        collect.push(self.codes_only as u8);
+
+        // rng seed
+        collect.extend_from_slice(&self.seed.to_be_bytes());
+
+        collect.into_boxed_slice()
     }
 
-    pub fn deserialize(bytes: &[u8; 64]) -> Box<Graph> {
+    // fields missing from older headers get their format 0 behaviour
+    pub fn deserialize(bytes: &[u8]) -> io::Result<Box<Graph>> {
+        if bytes.len() < 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "graph header is too short",
+            ));
+        }
         let dimension = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
         let entrence_point_index = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
         let layer_count = u64::from_be_bytes(bytes[24..32].try_into().unwrap());
@@ -212,26 +381,195 @@ impl Graph {
         let m_max = u64::from_be_bytes(bytes[40..48].try_into().unwrap());
         let m_max0 = u64::from_be_bytes(bytes[48..56].try_into().unwrap());
         let candidate = u64::from_be_bytes(bytes[56..64].try_into().unwrap());
+        let metric = match bytes.get(64) {
+            Some(&b) => Metric::from_byte(b).ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown metric {}", b),
+            ))?,
+            None => Metric::Cosine,
+        };
+        let element_type = match bytes.get(65) {
+            Some(&b) => ElementType::from_byte(b).ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown element type {}", b),
+            ))?,
+            None => ElementType::F64,
+        };
+        let (quantizer, quantizer_size) = match bytes.get(66..) {
+            Some(bytes) => Quantizer::deserialize(bytes, dimension as usize)?,
+            None => (None, 0),
+        };
+        let codes_only = bytes.get(66 + quantizer_size) == Some(&1);
+        // the seed follows, graphs from before it was kept get a new one
+        let seed = match bytes.get(66 + quantizer_size + 1..) {
+            Some(bytes) if bytes.len() >= 8 => u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            _ => rand::random(),
+        };
 
-        Box::new(Graph {
+        Ok(Box::new(Graph {
             entrence_point: EntrencePoint::Index(entrence_point_index as usize),
             layer_count: layer_count as usize,
-            m_l: m_l,
+            m_l,
             m_max: m_max as usize,
             m_max0: m_max0 as usize,
             candidate_list_size: candidate as usize,
             nodes: Vec::new(),
             dimension: dimension as usize,
-        })
+            metric,
+            element_type,
+            quantizer,
+            codes_only,
+            keys: HashMap::new(),
+            seed,
+        }))
+    }
+
+    // rebuilds `keys` from the nodes, after reading them from a file
+    pub fn index_keys(&mut self) {
+        self.keys = self
+            .nodes
+            .iter()
+            .filter_map(|n| {
+                let n = n.borrow();
+                match &n.key {
+                    Some(key) if !n.deleted => Some((key.clone(), n.index)),
+                    _ => None,
+                }
+            })
+            .collect();
+    }
+
+    pub fn id_of(&self, key: &str) -> Option<usize> {
+        self.keys.get(key).copied()
     }
 
-    pub fn try_weaken_ep(&mut self) {
-        match &self.entrence_point {
-            EntrencePoint::Index(index) => {
-                self.entrence_point = EntrencePoint::Weak(Rc::downgrade(&self.nodes[*index]))
+    /*
+    Inserts `q` under `key`. A key that is already in use is rejected with the id
+    using it, unless `upsert` is set, then that node is deleted and the key moves
+    to the new one.
+    */
+    pub fn insert_with_key(&mut self, q: &[f64], key: &str, upsert: bool) -> Result<usize, usize> {
+        if let Some(existing) = self.id_of(key) {
+            if !upsert {
+                return Err(existing);
             }
-            _ => (),
+            self.delete(existing);
         }
+        let id = self.insert(q);
+        self.nodes[id].borrow_mut().key = Some(key.to_string());
+        self.keys.insert(key.to_string(), id);
+        Ok(id)
+    }
+
+    // marks a node deleted, false if it already was
+    pub fn delete(&mut self, id: usize) -> bool {
+        let mut node = self.nodes[id].borrow_mut();
+        if node.deleted {
+            return false;
+        }
+        node.deleted = true;
+        if let Some(key) = &node.key {
+            self.keys.remove(key);
+        }
+        true
+    }
+
+    /*
+    Swaps the quantizer and re-encodes every node, `None` drops the codes. A graph
+    that only kept codes gets its vectors back first, decoded from the old codes,
+    so they are only as good as those were.
+    */
+    pub fn quantize(&mut self, quantizer: Option<Quantizer>) {
+        if self.codes_only {
+            for node in &self.nodes {
+                let vector = Vector::encode(self.element_type, &self.decoded(&node.borrow()));
+                node.borrow_mut().vector = vector;
+            }
+            self.codes_only = false;
+        }
+        for node in &self.nodes {
+            let mut node = node.borrow_mut();
+            node.codes = match &quantizer {
+                Some(quantizer) => quantizer.encode(&node.vector.to_f64()),
+                None => Box::new([]),
+            };
+        }
+        self.quantizer = quantizer;
+    }
+
+    /*
+    Keeps only each node's codes, which is what makes a quantizer save memory.
+    Searches then walk and rank by the codes alone, there is nothing to re-rank
+    with, and inserts are placed by them too. Needs a quantizer that has encoded
+    every node.
+    */
+    pub fn drop_vectors(&mut self) -> Result<(), String> {
+        if self.quantizer.is_none() {
+            return Err("only a quantized graph can drop its vectors".to_string());
+        }
+        if self.nodes.iter().any(|n| n.borrow().codes.is_empty()) {
+            return Err("every node needs codes before the vectors can be dropped".to_string());
+        }
+        for node in &self.nodes {
+            node.borrow_mut().vector = Vector::encode(self.element_type, &[]);
+        }
+        self.codes_only = true;
+        Ok(())
+    }
+
+    // a node's vector, decoded from its codes if the graph only kept those
+    pub fn vector(&self, id: usize) -> Vec<f64> {
+        self.decoded(&self.nodes[id].borrow())
+    }
+
+    fn decoded(&self, node: &Node) -> Vec<f64> {
+        match &self.quantizer {
+            Some(quantizer) if node.vector.is_empty() && !node.codes.is_empty() => {
+                quantizer.decode(&node.codes, self.dimension)
+            }
+            _ => node.vector.to_f64(),
+        }
+    }
+
+    // every vector in the graph, for training quantizers
+    pub fn vectors(&self) -> Vec<Vec<f64>> {
+        self.nodes
+            .iter()
+            .map(|n| self.decoded(&n.borrow()))
+            .collect()
+    }
+
+    pub fn try_weaken_ep(&mut self) -> io::Result<()> {
+        if let EntrencePoint::Index(index) = &self.entrence_point {
+            let node = self
+                .nodes
+                .get(*index)
+                .ok_or_else(|| damaged(&format!("entrence point {} does not exist", index)))?;
+            self.entrence_point = EntrencePoint::Weak(Rc::downgrade(node))
+        }
+        Ok(())
+    }
+
+    /*
+    Friends read from a file are indices, this swaps them for pointers so the
+    graph can be searched. Searches follow friends without looking, so a graph
+    that `check` finds anything wrong with is refused instead.
+    */
+    pub fn try_link_friends(&mut self) -> io::Result<()> {
+        if let Some(violation) = check(self).first() {
+            return Err(damaged(&format!("the graph is damaged: {}", violation)));
+        }
+        for node in &self.nodes {
+            let mut node = node.borrow_mut();
+            for layer in node.friend_layers.iter_mut() {
+                for friend in layer.iter_mut() {
+                    if let Some(ptr) = friend.try_into_ptr(self) {
+                        *friend = ptr;
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -253,7 +591,7 @@ impl Graph {
             }
             node.borrow().print();
         }
-        print!("\n");
+        println!();
     }
 }
 
@@ -261,10 +599,19 @@ pub struct Node {
     pub index: usize,
     pub friend_layers: Vec<Vec<NodePtr>>,
     pub max_level: usize,
-    pub vector: Box<[f64]>,
+    pub vector: Vector,
+    // compressed vector from the graph's quantizer, empty if there is none
+    pub codes: Box<[u8]>,
+    // |vector|, cached for cosine distances
+    pub norm: f64,
+    pub payload: Payload,
+    // id the caller knows this node by
+    pub key: Option<String>,
+    // deleted nodes stay in the graph so it stays connected, searches skip them
+    pub deleted: bool,
 }
 
-type NodeRef = Rc<RefCell<Node>>;
+pub type NodeRef = Rc<RefCell<Node>>;
 type NodeWeak = Weak<RefCell<Node>>;
 
 #[derive(Clone)]
@@ -282,7 +629,7 @@ impl NodePtr {
         }
     }
 
-    fn _index(&self) -> Option<&usize> {
+    fn index(&self) -> Option<&usize> {
         if let NodePtr::Index(index) = self {
             Some(index)
         } else {
@@ -290,90 +637,153 @@ impl NodePtr {
         }
     }
 
-    fn _try_into_ptr(&mut self, g: &Graph) -> Option<NodePtr> {
-        let index = self._index().unwrap();
-        Some(NodePtr::Ptr(g.nodes[*index].clone()))
+    fn try_into_ptr(&self, g: &Graph) -> Option<NodePtr> {
+        let index = self.index()?;
+        Some(NodePtr::Ptr(g.nodes.get(*index)?.clone()))
+    }
+
+    // node index of the friend, whichever way it is stored
+    pub fn id(&self) -> usize {
+        match self {
+            NodePtr::Ptr(ptr) => ptr.borrow().index,
+            NodePtr::Index(index) => *index,
+        }
     }
 }
 
 impl Node {
-    pub fn new(index: usize, max_level: usize, vector: &[f64]) -> NodeRef {
+    pub fn new(index: usize, max_level: usize, vector: Vector) -> NodeRef {
         Rc::new(RefCell::new(Node {
-            index: index,
-            max_level: max_level,
-            vector: vector.into(),
-            friend_layers: vec![Vec::new()],
+            index,
+            max_level,
+            norm: vector.norm(),
+            vector,
+            codes: Box::new([]),
+            payload: Payload::default(),
+            key: None,
+            deleted: false,
+            // one per layer the node lives on, 0 through max_level
+            friend_layers: vec![Vec::new(); max_level + 1],
         }))
     }
 
     pub fn serialize(&self) -> Box<[u8]> {
         let mut collect: Vec<u8> = vec![0, 0, 0, 0];
 
-        self.index
+        (self.index as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
-        self.max_level
+        (self.max_level as u64)
             .to_be_bytes()
             .iter()
             .for_each(|&x| collect.push(x));
-        self.vector
-            .iter()
-            .for_each(|x| x.to_be_bytes().iter().for_each(|&b| collect.push(b)));
+        self.vector.serialize(&mut collect);
 
         self.friend_layers.iter().for_each(|f| {
-            f.len().to_be_bytes().iter().for_each(|&b| collect.push(b));
+            (f.len() as u64)
+                .to_be_bytes()
+                .iter()
+                .for_each(|&b| collect.push(b));
             f.iter().for_each(|x| {
-                x.ptr()
-                    .unwrap()
-                    .borrow()
-                    .index
+                (x.id() as u64)
                     .to_be_bytes()
                     .iter()
                     .for_each(|&b| collect.push(b))
             })
         });
 
-        let len_bytes = (collect.len() - 4).to_be_bytes();
-        collect[0] = len_bytes[4];
-        collect[1] = len_bytes[5];
-        collect[2] = len_bytes[6];
-        collect[3] = len_bytes[7];
+        (self.codes.len() as u64)
+            .to_be_bytes()
+            .iter()
+            .for_each(|&b| collect.push(b));
+        collect.extend_from_slice(&self.codes);
+        collect.extend_from_slice(&self.norm.to_be_bytes());
+        self.payload.serialize(&mut collect);
+        collect.push(self.deleted as u8 | (self.key.is_some() as u8) << 1);
+        if let Some(key) = &self.key {
+            collect.extend_from_slice(&(key.len() as u64).to_be_bytes());
+            collect.extend_from_slice(key.as_bytes());
+        }
+
+        // the size is 4 bytes whatever the width of usize
+        let len_bytes = ((collect.len() - 4) as u32).to_be_bytes();
+        collect[..4].copy_from_slice(&len_bytes);
 
         collect.into_boxed_slice()
     }
 
     // assuming bytes[0] excludes the length bytes and starts at index
-    pub fn deserialize(bytes: &[u8], dimension: usize) -> NodeRef {
-        let index = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
-        let max_level = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
-        let mut vector: Vec<f64> = Vec::new();
-        let mut k = 16 + dimension * 8;
-        for x in bytes[16..k].chunks(8) {
-            vector.push(f64::from_be_bytes(x.try_into().unwrap()));
-        }
+    // and bytes ends where the node does
+    pub fn deserialize(
+        bytes: &[u8],
+        dimension: usize,
+        element_type: ElementType,
+    ) -> io::Result<NodeRef> {
+        let mut k = 0;
+        let index = take_u64(bytes, &mut k)?;
+        let max_level = take_u64(bytes, &mut k)?;
+        let vector_size = dimension
+            .checked_mul(element_type.size())
+            .ok_or_else(|| damaged("vector is too long"))?;
+        let vector = Vector::deserialize(element_type, take(bytes, &mut k, vector_size)?);
 
         let mut friends: Vec<Vec<NodePtr>> = vec![];
 
-        for i in 0..=max_level as usize {
-            let len = u64::from_be_bytes(bytes[k..k + 8].try_into().unwrap());
-            k += 8;
+        for i in 0..=max_level {
+            let len = take_u64(bytes, &mut k)?;
 
             friends.push(Vec::new());
             for _j in 0..len {
-                friends[i].push(NodePtr::Index(u64::from_be_bytes(
-                    bytes[k..k + 8].try_into().unwrap(),
-                ) as usize));
-                k += 8;
+                friends[i as usize].push(NodePtr::Index(take_u64(bytes, &mut k)? as usize));
             }
         }
 
-        Rc::new(RefCell::new(Node {
+        // nodes written before quantization stop after their friends
+        let mut codes: Box<[u8]> = Box::new([]);
+        if k < bytes.len() {
+            let len = take_u64(bytes, &mut k)? as usize;
+            codes = take(bytes, &mut k, len)?.into();
+        }
+
+        // and before norms were cached
+        let norm = match bytes.get(k..k + 8) {
+            Some(norm) => f64::from_be_bytes(norm.try_into().unwrap()),
+            None => vector.norm(),
+        };
+        k += 8;
+
+        // and before payloads
+        let mut payload = Payload::default();
+        if k < bytes.len() {
+            let len;
+            (payload, len) = Payload::deserialize(&bytes[k..])?;
+            k += len;
+        }
+
+        // and before keys and deletes
+        let mut key = None;
+        let mut deleted = false;
+        if k < bytes.len() {
+            let flags = take(bytes, &mut k, 1)?[0];
+            deleted = flags & 1 != 0;
+            if flags & 2 != 0 {
+                let len = take_u64(bytes, &mut k)? as usize;
+                key = Some(String::from_utf8_lossy(take(bytes, &mut k, len)?).into_owned());
+            }
+        }
+
+        Ok(Rc::new(RefCell::new(Node {
             index: index as usize,
             friend_layers: friends,
             max_level: max_level as usize,
-            vector: vector.into_boxed_slice(),
-        }))
+            vector,
+            codes,
+            norm,
+            payload,
+            key,
+            deleted,
+        })))
     }
 
     #[allow(dead_code)]
@@ -389,21 +799,33 @@ impl Node {
                 }
             }
         }
-        print!("\n");
+        println!();
         std::io::stdout().flush().expect("stinky");
     }
 }
 
-fn push_friend(
-    node: NodeRef,
-    friend: NodeRef,
-    level: usize,
-    m_max: usize,
-    m_max0: usize,
-    propagate: bool,
-) {
+fn damaged(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// the next `len` bytes of a node, a node that ends early is damaged
+fn take<'a>(bytes: &'a [u8], k: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let taken = k
+        .checked_add(len)
+        .and_then(|end| bytes.get(*k..end))
+        .ok_or_else(|| damaged("node is cut short"))?;
+    *k += len;
+    Ok(taken)
+}
+
+fn take_u64(bytes: &[u8], k: &mut usize) -> io::Result<u64> {
+    Ok(u64::from_be_bytes(take(bytes, k, 8)?.try_into().unwrap()))
+}
+
+// `g` is only asked for its parameters, `node` and `friend` are linked through their pointers
+fn push_friend(node: NodeRef, friend: NodeRef, level: usize, g: &Graph, propagate: bool) {
     if propagate {
-        push_friend(friend.clone(), node.clone(), level, m_max, m_max0, false);
+        push_friend(friend.clone(), node.clone(), level, g, false);
     }
     let node_bind = node.clone();
     {
@@ -417,17 +839,18 @@ fn push_friend(
         if let Some(m) = shrinkable(
             node_iborrow.friend_layers[level].len(),
             level,
-            m_max,
-            m_max0,
+            g.m_max,
+            g.m_max0,
         ) {
-            new_neighbors = select_neighbors_simple(
-                &node_iborrow.vector.clone(),
+            new_neighbors = select_neighbors(
+                &Query::of_node(&node_iborrow, g),
                 &node_iborrow.friend_layers[level],
                 m,
+                g,
             );
         }
     }
-    if new_neighbors.len() > 0 {
+    if !new_neighbors.is_empty() {
         node_bind.borrow_mut().friend_layers[level] = new_neighbors.clone();
     }
 }
@@ -459,7 +882,8 @@ struct NodeHeapItem {
 
 impl Ord for NodeHeapItem {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.distance.partial_cmp(&other.distance).unwrap()
+        // a nan distance from a nan component gets an order instead of a panic
+        self.distance.total_cmp(&other.distance)
     }
 }
 
@@ -471,107 +895,372 @@ impl PartialOrd for NodeHeapItem {
 
 impl PartialEq for NodeHeapItem {
     fn eq(&self, other: &Self) -> bool {
-        self.distance == other.distance
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for NodeHeapItem {}
 
-pub fn knn_search(g: &Graph, q: &[f64], k: usize, ef: usize) -> Vec<NodeRef> {
-    let mut candidates = BinaryHeap::new();
-    let mut entrence_point = g.entrence_point.weak().unwrap().upgrade().unwrap();
-    let level = g.layer_count - 1;
+/*
+How `search_layer` measures the distance from the query to a node. Quantized
+queries compare against node codes and fall back to vectors for nodes without any.
+In a graph that only keeps codes even the exact distance is measured against them.
+*/
+struct Query<'a> {
+    vector: Vector,
+    // worked out once per search
+    norm: f64,
+    metric: Metric,
+    quantized: Option<PreparedQuery<'a>>,
+}
 
-    for l in (1..=level).rev() {
-        let tmp = search_layer(q, entrence_point, 1, l)[0].clone();
-        candidates.push(Reverse(NodeHeapItem {
-            distance: cosine_distance(q, &tmp.clone().ptr().unwrap().borrow().vector),
-            node: tmp.ptr().unwrap().clone(),
-        }));
+impl<'a> Query<'a> {
+    fn exact(q: &Vector, metric: Metric) -> Self {
+        Query {
+            vector: q.clone(),
+            norm: q.norm(),
+            metric,
+            quantized: None,
+        }
+    }
 
-        entrence_point = candidates.pop().unwrap().0.node;
+    // uses the graph's quantizer if it has one
+    fn new(g: &'a Graph, q: &[f64]) -> Self {
+        let vector = Vector::encode(g.element_type, q);
+        Query {
+            norm: vector.norm(),
+            vector,
+            metric: g.metric,
+            quantized: g
+                .quantizer
+                .as_ref()
+                .map(|quantizer| quantizer.prepare(g.metric, q)),
+        }
     }
 
-    search_layer(q, entrence_point, ef, 0).iter().for_each(|x| {
-        candidates.push(Reverse(NodeHeapItem {
-            distance: cosine_distance(q, &x.ptr().unwrap().borrow().vector),
-            node: x.ptr().unwrap().clone(),
-        }))
-    });
+    // measures from a node already in the graph, reusing its norm or decoding its codes
+    fn of_node(node: &Node, g: &'a Graph) -> Self {
+        match g.codes_only {
+            true => Query::new(g, &g.decoded(node)),
+            false => Query {
+                vector: node.vector.clone(),
+                norm: node.norm,
+                metric: g.metric,
+                quantized: None,
+            },
+        }
+    }
+
+    fn distance(&self, node: &Node) -> f64 {
+        match &self.quantized {
+            Some(prepared) if !node.codes.is_empty() => prepared.distance(&node.codes),
+            _ => self.vector_distance(node),
+        }
+    }
+
+    // the full vectors, unless the node only kept its codes
+    fn exact_distance(&self, node: &Node) -> f64 {
+        if node.vector.is_empty() && !node.codes.is_empty() {
+            return self.distance(node);
+        }
+        self.vector_distance(node)
+    }
+
+    fn vector_distance(&self, node: &Node) -> f64 {
+        self.metric
+            .distance_with_norms(&self.vector, self.norm, &node.vector, node.norm)
+    }
+}
+
+pub fn knn_search(g: &Graph, q: &[f64], k: usize, ef: usize) -> Vec<SearchResult> {
+    search(g, q, k, ef, &|_| true, &mut Spent::unlimited())
+}
+
+/*
+`knn_search`, or `knn_search_filtered` with a filter, that stops once `budget`
+runs out. The entry point of each layer is always measured, so a search can go
+over a distance budget by at most the number of layers.
+*/
+pub fn knn_search_budgeted(
+    g: &Graph,
+    q: &[f64],
+    k: usize,
+    ef: usize,
+    filter: Option<&dyn Filter>,
+    budget: Budget,
+) -> BudgetedSearch {
+    let mut spent = Spent::new(budget);
+    let results = match filter {
+        Some(filter) => filtered_search(g, q, k, ef, filter, &mut spent),
+        None => search(g, q, k, ef, &|_| true, &mut spent),
+    };
+    BudgetedSearch {
+        results,
+        partial: spent.exhausted,
+    }
+}
+
+// below this fraction of allowed nodes a filtered search just compares against all of them
+const BRUTE_FORCE_FRACTION: f64 = 0.02;
+// filters that can't say how many ids they allow are asked about this many nodes
+const FILTER_SAMPLE: usize = 512;
+
+/*
+Nearest neighbors among the nodes `filter` allows. The graph is still walked
+through every node, only the results are limited to allowed ones. When so few
+nodes are allowed that the walk would mostly see disallowed ones, it falls
+back to comparing against every allowed node.
+*/
+pub fn knn_search_filtered(
+    g: &Graph,
+    q: &[f64],
+    k: usize,
+    ef: usize,
+    filter: &dyn Filter,
+) -> Vec<SearchResult> {
+    filtered_search(g, q, k, ef, filter, &mut Spent::unlimited())
+}
+
+fn filtered_search(
+    g: &Graph,
+    q: &[f64],
+    k: usize,
+    ef: usize,
+    filter: &dyn Filter,
+    spent: &mut Spent,
+) -> Vec<SearchResult> {
+    let allowed = allowed_estimate(g, filter);
+    if allowed <= ef.max(k) || (allowed as f64) < g.nodes.len() as f64 * BRUTE_FORCE_FRACTION {
+        return brute_force(g, q, k, filter, spent);
+    }
+    search(g, q, k, ef, filter, spent)
+}
+
+/*
+Roughly how many nodes `filter` allows, without asking it about every node.
+Filters that know their count give it. Others are asked about evenly spaced
+nodes and the share they allow is scaled up, which is exact for graphs no
+bigger than the sample.
+*/
+fn allowed_estimate(g: &Graph, filter: &dyn Filter) -> usize {
+    if let Some(count) = filter.allowed_count() {
+        return count;
+    }
+    let n = g.nodes.len();
+    let sample = n.min(FILTER_SAMPLE);
+    if sample == 0 {
+        return 0;
+    }
+    let allowed = (0..sample)
+        .map(|i| i * n / sample)
+        .filter(|&id| !g.nodes[id].borrow().deleted && filter.allows(id))
+        .count();
+    allowed * n / sample
+}
+
+// deleted nodes are walked through like any other, they are just never returned
+fn search(
+    g: &Graph,
+    q: &[f64],
+    k: usize,
+    ef: usize,
+    filter: &dyn Filter,
+    spent: &mut Spent,
+) -> Vec<SearchResult> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let live = |id: usize| !g.nodes[id].borrow().deleted && filter.allows(id);
+    // the beam has to be at least as wide as the number of results we want
+    let ef = ef.max(k);
+    let query = Query::new(g, q);
+    let mut entrence_point = g.entrence_point.weak().unwrap().upgrade().unwrap();
+    let top = entrence_point.borrow().max_level;
+
+    // greedy descent, the nearest node on each layer is where the next one starts
+    for l in (1..=top).rev() {
+        entrence_point = search_layer(&query, entrence_point, 1, l, None, spent)
+            .swap_remove(0)
+            .node;
+    }
+
+    let mut found = search_layer(&query, entrence_point, ef, 0, Some(&live), spent);
+    if !g.codes_only
+        && g.quantizer
+            .as_ref()
+            .is_some_and(|quantizer| quantizer.rerank())
+    {
+        // codes only approximate the distance, re-rank everything we found with the real vectors
+        found.iter_mut().for_each(|a| {
+            a.distance = query.exact_distance(&a.node.borrow());
+        });
+        found.sort();
+    }
 
-    return candidates
-        .into_sorted_vec()
+    found
         .into_iter()
-        .rev()
         .take(k)
-        .map(|a| a.0.node)
-        .collect::<Vec<NodeRef>>();
+        .map(|a| SearchResult {
+            id: a.node.borrow().index,
+            distance: a.distance,
+        })
+        .collect::<Vec<SearchResult>>()
+}
+
+// exact search over every node, slow but always right
+pub fn brute_force_search(g: &Graph, q: &[f64], k: usize) -> Vec<SearchResult> {
+    brute_force_search_filtered(g, q, k, &|_| true)
+}
+
+pub fn brute_force_search_filtered(
+    g: &Graph,
+    q: &[f64],
+    k: usize,
+    filter: &dyn Filter,
+) -> Vec<SearchResult> {
+    brute_force(g, q, k, filter, &mut Spent::unlimited())
 }
 
-fn select_neighbors_simple(q: &[f64], c: &Vec<NodePtr>, m: usize) -> Vec<NodePtr> {
-    let mut nearest_heap = BinaryHeap::new();
-    for v in c {
-        nearest_heap.push(Reverse(NodeHeapItem {
-            distance: cosine_distance(q, &v.ptr().unwrap().borrow().vector),
+// with a budget only the nodes up to where it ran out are compared, each counts as visited and measured
+fn brute_force(
+    g: &Graph,
+    q: &[f64],
+    k: usize,
+    filter: &dyn Filter,
+    spent: &mut Spent,
+) -> Vec<SearchResult> {
+    // without vectors the codes are as exact as it gets
+    let query = match g.codes_only {
+        true => Query::new(g, q),
+        false => Query::exact(&Vector::encode(g.element_type, q), g.metric),
+    };
+    let mut results = g
+        .nodes
+        .iter()
+        .filter(|n| !n.borrow().deleted && filter.allows(n.borrow().index))
+        .take_while(|_| spent.visit() && spent.measure())
+        .map(|n| SearchResult {
+            id: n.borrow().index,
+            distance: query.exact_distance(&n.borrow()),
+        })
+        .collect::<Vec<SearchResult>>();
+    results.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    results.truncate(k);
+    results
+}
+
+/*
+Neighbor selection heuristic from the HNSW paper: candidates are taken nearest
+first and kept only when they are closer to `q` than to every neighbor kept so
+far, so the links point in different directions instead of all into one
+cluster. Candidates it passes over fill whatever room is left, a node that is
+only near its own cluster keeps a way in.
+*/
+fn select_neighbors(q: &Query, c: &[NodePtr], m: usize, g: &Graph) -> Vec<NodePtr> {
+    let mut nearest: Vec<NodeHeapItem> = c
+        .iter()
+        .map(|v| NodeHeapItem {
+            distance: q.exact_distance(&v.ptr().unwrap().borrow()),
             node: v.ptr().unwrap().clone(),
-        }));
-    }
+        })
+        .collect();
+    nearest.sort();
 
-    let mut res: Vec<NodePtr> = Vec::new();
-    for _i in 0..min(m, nearest_heap.len()) {
-        let Reverse(nearest) = nearest_heap.pop().unwrap();
-        res.push(NodePtr::Ptr(nearest.node));
+    let mut kept: Vec<NodeRef> = Vec::new();
+    let mut passed_over: Vec<NodeRef> = Vec::new();
+    for e in nearest {
+        if kept.len() == m {
+            break;
+        }
+        let from_e = Query::of_node(&e.node.borrow(), g);
+        if kept
+            .iter()
+            .all(|r| e.distance < from_e.exact_distance(&r.borrow()))
+        {
+            kept.push(e.node);
+        } else {
+            passed_over.push(e.node);
+        }
     }
+    let room = m - kept.len();
+    kept.extend(passed_over.into_iter().take(room));
 
-    return res;
+    kept.into_iter().map(NodePtr::Ptr).collect()
 }
 
 /*
 `ep` must be on the same layer as `layer`
+returns the `count` nearest nodes found on `layer`, nearest first
+with a `filter` every node is walked through but only allowed ones are returned
+once `spent` runs out the walk stops, `ep` is measured regardless
  */
-fn search_layer(q: &[f64], ep: NodeRef, count: usize, layer: usize) -> Vec<NodePtr> {
+fn search_layer(
+    q: &Query,
+    ep: NodeRef,
+    count: usize,
+    layer: usize,
+    filter: Option<&dyn Filter>,
+    spent: &mut Spent,
+) -> Vec<NodeHeapItem> {
     assert!(ep.borrow().max_level >= layer);
-    let mut visited = Vec::new();
+    if count == 0 {
+        return Vec::new();
+    }
+    let allowed = |node: &NodeRef| filter.is_none_or(|f| f.allows(node.borrow().index));
+    let mut visited = HashSet::new();
     let mut candidates = BinaryHeap::new();
     let mut found = BinaryHeap::new();
 
-    let init_dist = cosine_distance(q, &ep.borrow().vector);
+    spent.measure();
+    let init_dist = q.distance(&ep.borrow());
 
-    visited.push(ep.clone());
+    visited.insert(ep.borrow().index);
     candidates.push(Reverse(NodeHeapItem {
         distance: init_dist,
         node: ep.clone(),
     })); // top of heap is nearest to q
 
-    found.push(NodeHeapItem {
-        distance: init_dist,
-        node: ep.clone(),
-    }); // top of heap is furthest from q
-
-    assert!(found.len() == 1);
-    while candidates.len() > 0 {
-        let Reverse(candidate) = candidates.pop().unwrap();
-        let mut furthest = found.peek().unwrap();
+    if allowed(&ep) {
+        found.push(NodeHeapItem {
+            distance: init_dist,
+            node: ep,
+        }); // top of heap is furthest from q
+    }
 
-        if candidate.distance > furthest.distance {
+    while let Some(Reverse(candidate)) = candidates.pop() {
+        // until found is full every candidate can still improve it
+        if found.len() >= count
+            && found
+                .peek()
+                .is_some_and(|f| candidate.distance > f.distance)
+        {
             break;
         }
+        if !spent.visit() {
+            break;
+        }
+
+        let candidate = candidate.node.borrow();
+        if let Some(friends) = candidate.friend_layers.get(layer) {
+            for e in friends {
+                let e = e.ptr().unwrap();
+                if !visited.insert(e.borrow().index) {
+                    continue;
+                }
+                if !spent.measure() {
+                    break;
+                }
 
-        if candidate.node.borrow().friend_layers.len() > layer {
-            for e in &candidate.node.borrow().friend_layers[layer] {
-                if !contains_rc(&visited, e.ptr().unwrap().clone()) {
-                    visited.push(e.ptr().unwrap().clone());
-                    furthest = found.peek().unwrap();
-                    if cosine_distance(&e.ptr().unwrap().borrow().vector, q) < furthest.distance
-                        || found.len() < count
-                    {
-                        candidates.push(Reverse(NodeHeapItem {
-                            distance: cosine_distance(q, &e.ptr().unwrap().borrow().vector),
-                            node: e.ptr().unwrap().clone(),
-                        }));
+                let distance = q.distance(&e.borrow());
+                if found.len() < count || found.peek().is_some_and(|f| distance < f.distance) {
+                    candidates.push(Reverse(NodeHeapItem {
+                        distance,
+                        node: e.clone(),
+                    }));
+                    if allowed(e) {
                         found.push(NodeHeapItem {
-                            distance: cosine_distance(q, &e.ptr().unwrap().borrow().vector),
-                            node: e.ptr().unwrap().clone(),
+                            distance,
+                            node: e.clone(),
                         });
                         if found.len() > count {
                             found.pop();
@@ -581,49 +1270,21 @@ fn search_layer(q: &[f64], ep: NodeRef, count: usize, layer: usize) -> Vec<NodeP
             }
         }
     }
-    return found
-        .into_sorted_vec()
-        .into_iter()
-        .rev()
-        .map(|a| NodePtr::Ptr(a.node))
-        .collect::<Vec<NodePtr>>();
+    found.into_sorted_vec()
 }
 
-pub fn cosine_distance(a: &[f64], b: &[f64]) -> f64 {
-    let mut num = 0.0;
-    for i in 0..a.len() {
-        num += a[i] * b[i];
-    }
-
-    let mut sum = 0.0;
-    for f in a {
-        sum += f.powi(2);
-    }
-    let mut dem = sum.sqrt();
-    sum = 0.0;
-    for f in b {
-        sum += f.powi(2);
-    }
-    dem = dem * sum.sqrt();
+// no node goes higher, m_l from a damaged file can't ask for a node with billions of layers
+const MAX_LEVEL: usize = 32;
 
-    return 1.0 - (num / dem);
-}
-
-fn calc_level(m_l: f64) -> usize {
-    let mut rng = rand::thread_rng();
+// node `index` always gets the same level for a given seed, however the graph got to it
+fn calc_level(m_l: f64, seed: u64, index: usize) -> usize {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(index as u64);
     let side = Uniform::new(0_f64, 1_f64);
-    let sample = rng.sample(side);
-
-    return (-sample.ln() * m_l).floor() as usize; //potentially fuckywucky
-}
+    // in (0, 1] so the log stays finite
+    let sample = 1.0 - rng.sample(side);
 
-fn contains_rc(v: &Vec<NodeRef>, n: NodeRef) -> bool {
-    for e in v {
-        if Rc::ptr_eq(&e, &n) {
-            return true;
-        }
-    }
-    return false;
+    ((-sample.ln() * m_l).floor() as usize).min(MAX_LEVEL)
 }
 
 fn shrinkable(friends_count: usize, layer: usize, m_max: usize, m_max0: usize) -> Option<usize> {
@@ -638,3 +1299,554 @@ fn shrinkable(friends_count: usize, layer: usize, m_max: usize, m_max0: usize) -
     }
     None
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+    use crate::check::{check, unreachable, Violation};
+    use crate::filter::IdSet;
+    use crate::quantize::{BinaryQuantizer, ProductQuantizer, ScalarQuantizer};
+    use crate::stats::GraphStats;
+
+    // `count` vectors with components in 0..1, the same seed builds the same graph
+    pub(crate) fn random_graph(
+        dimension: usize,
+        count: usize,
+        metric: Metric,
+        element_type: ElementType,
+        seed: u64,
+    ) -> Graph {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let first = random_vector(&mut rng, dimension);
+        let mut g = Graph::new(&first, 8.0, 8, 16, 64, metric, element_type, seed).unwrap();
+        for _i in 1..count {
+            g.insert(&random_vector(&mut rng, dimension));
+        }
+        g
+    }
+
+    pub(crate) fn random_vector(rng: &mut impl Rng, dimension: usize) -> Vec<f64> {
+        (0..dimension).map(|_| rng.gen()).collect()
+    }
+
+    // share of the true `k` nearest neighbors that `knn_search` finds, over `queries`
+    fn recall(g: &Graph, queries: &[Vec<f64>], k: usize) -> f64 {
+        let mut hits = 0;
+        let mut total = 0;
+        for q in queries {
+            let exact = brute_force_search(g, q, k);
+            let search = knn_search(g, q, k, 64);
+            assert_eq!(search.len(), exact.len());
+            assert!(search.windows(2).all(|w| w[0].distance <= w[1].distance));
+            total += exact.len();
+            hits += exact
+                .iter()
+                .filter(|e| search.iter().any(|s| s.id == e.id))
+                .count();
+        }
+        hits as f64 / total as f64
+    }
+
+    fn queries(rng: &mut impl Rng, dimension: usize) -> Vec<Vec<f64>> {
+        (0..100).map(|_| random_vector(rng, dimension)).collect()
+    }
+
+    // hnsw should find (almost) the same neighbors as checking every node
+    #[test]
+    fn recall_matches_brute_force() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for metric in [Metric::Cosine, Metric::Euclidean, Metric::Dot] {
+            let g = random_graph(8, 500, metric, ElementType::F64, 1);
+            let recall = recall(&g, &queries(&mut rng, 8), 10);
+            assert!(recall >= 0.95, "{} recall@10: {}", metric.name(), recall);
+        }
+    }
+
+    #[test]
+    fn quantized_recall() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for metric in [Metric::Cosine, Metric::Euclidean, Metric::Dot] {
+            let mut g = random_graph(8, 500, metric, ElementType::F64, 1);
+            let queries = queries(&mut rng, 8);
+
+            // int8 codes walk the graph, the re-rank should keep recall close
+            let sq = ScalarQuantizer::train(&g.vectors());
+            g.quantize(Some(Quantizer::Scalar(sq)));
+            let int8 = recall(&g, &queries, 10);
+            assert!(int8 >= 0.9, "{} int8 recall@10: {}", metric.name(), int8);
+
+            // pq codes are much coarser, the re-rank can only fix what the walk found
+            let pq = ProductQuantizer::new(8, 4, 64).unwrap();
+            g.quantize(Some(Quantizer::Product(pq.train(&g.vectors(), g.seed))));
+            let pq = recall(&g, &queries, 10);
+            assert!(pq >= 0.8, "{} pq recall@10: {}", metric.name(), pq);
+        }
+    }
+
+    #[test]
+    fn binary_recall() {
+        // sign bits only mean something for vectors centered on zero
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut centered = || -> Vec<f64> { (0..32).map(|_| rng.gen_range(-1.0..1.0)).collect() };
+        let mut g = Graph::new(
+            &centered(),
+            8.0,
+            8,
+            16,
+            32,
+            Metric::Cosine,
+            ElementType::F32,
+            0,
+        )
+        .unwrap();
+        g.quantize(Some(Quantizer::Binary(BinaryQuantizer { rerank: true })));
+        for _i in 0..500 {
+            g.insert(&centered());
+        }
+        let queries: Vec<Vec<f64>> = (0..100).map(|_| centered()).collect();
+        let recall = recall(&g, &queries, 10);
+        assert!(recall >= 0.7, "binary recall@10: {}", recall);
+    }
+
+    // the graph header and every node, what the file holds for a collection
+    pub(crate) fn graph_bytes(g: &Graph) -> Vec<u8> {
+        let mut bytes = g.serialize().to_vec();
+        g.nodes
+            .iter()
+            .for_each(|n| bytes.extend_from_slice(&n.borrow().serialize()));
+        bytes
+    }
+
+    // the same vectors inserted in the same order, into a graph with `seed`
+    pub(crate) fn seeded_graph(seed: u64, count: usize) -> Graph {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut g = Graph::new(
+            &[0.5; 4],
+            4.0,
+            8,
+            16,
+            32,
+            Metric::Euclidean,
+            ElementType::F64,
+            seed,
+        )
+        .unwrap();
+        for _i in 1..count {
+            g.insert(&random_vector(&mut rng, 4));
+        }
+        g
+    }
+
+    #[test]
+    fn seeds_make_builds_repeatable() {
+        // the same seed and inserts build the same graph, a different seed a different one
+        let g = seeded_graph(42, 200);
+        assert_eq!(graph_bytes(&g), graph_bytes(&seeded_graph(42, 200)));
+        assert_ne!(graph_bytes(&g), graph_bytes(&seeded_graph(43, 200)));
+    }
+
+    #[test]
+    fn filtered_recall() {
+        let g = random_graph(8, 1000, Metric::Euclidean, ElementType::F64, 0);
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        // a third or a tenth of the graph allowed walks it, a handful of ids falls back to brute force
+        let every_third = |id: usize| id.is_multiple_of(3);
+        let every_tenth: IdSet = (0..g.nodes.len()).step_by(10).collect();
+        let few: IdSet = (0..g.nodes.len()).step_by(97).collect();
+        let filters: [&dyn Filter; 3] = [&every_third, &every_tenth, &few];
+        for filter in filters {
+            let mut hits = 0;
+            let mut total = 0;
+            for q in queries(&mut rng, 8) {
+                let exact = brute_force_search_filtered(&g, &q, 10, filter);
+                let search = knn_search_filtered(&g, &q, 10, 64, filter);
+                assert!(search.iter().all(|s| filter.allows(s.id)));
+                assert_eq!(search.len(), exact.len());
+                total += exact.len();
+                hits += exact
+                    .iter()
+                    .filter(|e| search.iter().any(|s| s.id == e.id))
+                    .count();
+            }
+            let recall = hits as f64 / total as f64;
+            assert!(recall >= 0.95, "filtered recall@10: {}", recall);
+        }
+    }
+
+    #[test]
+    fn nothing_asked_for_nothing_found() {
+        let g = random_graph(4, 100, Metric::Euclidean, ElementType::F64, 0);
+        let q = [0.5; 4];
+        // the entry point is filtered out, so the walk starts with nothing found
+        let ep = g.entrence_point.weak().unwrap().upgrade().unwrap();
+        let ep = ep.borrow().index;
+        let others = |id: usize| id != ep;
+        assert!(knn_search(&g, &q, 0, 20).is_empty());
+        assert!(knn_search_filtered(&g, &q, 0, 20, &others).is_empty());
+        assert!(knn_search_filtered(&g, &q, 0, 0, &others).is_empty());
+        assert_eq!(knn_search_filtered(&g, &q, 5, 0, &others).len(), 5);
+    }
+
+    #[test]
+    fn keys_upserts_and_deletes() {
+        let mut g = random_graph(2, 1, Metric::Euclidean, ElementType::F64, 0);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for i in 0..50 {
+            let vec = random_vector(&mut rng, 2);
+            assert!(g.insert_with_key(&vec, &format!("k{}", i), false).is_ok());
+        }
+        assert_eq!(
+            g.insert_with_key(&[0.5, 0.5], "k3", false),
+            Err(g.id_of("k3").unwrap())
+        );
+
+        // upserting moves the key, the old node is gone from searches
+        let old = g.id_of("k3").unwrap();
+        let new = g.insert_with_key(&[0.5, 0.5], "k3", true).unwrap();
+        assert_eq!(g.id_of("k3"), Some(new));
+        assert!(g.nodes[old].borrow().deleted);
+        assert!(g.delete(g.id_of("k7").unwrap()));
+        // a node that is already deleted stays deleted
+        assert!(!g.delete(old));
+        assert_eq!(g.id_of("k7"), None);
+        for q in queries(&mut rng, 2) {
+            let search = knn_search(&g, &q, 50, 64);
+            assert!(search.iter().all(|s| !g.nodes[s.id].borrow().deleted));
+            // the first node, 50 keyed ones and the upsert, less the two deleted
+            assert_eq!(search.len(), 50);
+        }
+    }
+
+    #[test]
+    fn zero_vectors_under_cosine() {
+        let zero = [0.0; 4];
+        let mut g = random_graph(4, 50, Metric::Cosine, ElementType::F64, 0);
+        for _i in 0..5 {
+            g.insert(&zero);
+        }
+        let found = knn_search(&g, &zero, 10, 20);
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|r| r.distance == 1.0));
+
+        let sq = ScalarQuantizer::train(&g.vectors());
+        g.quantize(Some(Quantizer::Scalar(sq)));
+        assert_eq!(knn_search(&g, &zero, 10, 20).len(), 10);
+        assert!(knn_search(&g, &[1.0, 0.0, 0.0, 0.0], 60, 60)
+            .iter()
+            .all(|r| !r.distance.is_nan()));
+    }
+
+    #[test]
+    fn full_beam_is_exact() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let g = random_graph(4, 50, Metric::Euclidean, ElementType::F64, 1);
+        let q = random_vector(&mut rng, 4);
+        let exact = brute_force_search(&g, &q, 5);
+        let search = knn_search(&g, &q, 5, g.nodes.len());
+        assert!(exact.iter().zip(&search).all(|(e, s)| e.id == s.id));
+    }
+
+    #[test]
+    fn results_are_sorted_by_distance() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        for metric in [Metric::Cosine, Metric::Euclidean, Metric::Dot] {
+            let g = random_graph(4, 300, metric, ElementType::F64, 1);
+            for _i in 0..20 {
+                let q = random_vector(&mut rng, 4);
+                let search = knn_search(&g, &q, 10, 64);
+                assert_eq!(search.len(), 10);
+                assert!(search.windows(2).all(|w| w[0].distance <= w[1].distance));
+                for result in &search {
+                    let vector = g.nodes[result.id].borrow().vector.to_f64();
+                    assert_eq!(result.distance, metric.distance_slice(&q, &vector));
+                }
+                assert!(search[0].similarity(metric) >= search[9].similarity(metric));
+            }
+        }
+
+        // a node is its own nearest neighbor
+        let g = random_graph(4, 300, Metric::Euclidean, ElementType::F64, 1);
+        let q = g.nodes[123].borrow().vector.to_f64();
+        let search = knn_search(&g, &q, 1, 64);
+        assert_eq!((search[0].id, search[0].distance), (123, 0.0));
+    }
+
+    #[test]
+    fn layers_and_reachability() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for (m, m_max, m_max0) in [(2.0, 4, 8), (4.0, 8, 16), (16.0, 16, 32)] {
+            let seed: u64 = rng.gen();
+            let first = random_vector(&mut rng, 8);
+            let mut g = Graph::new(
+                &first,
+                m,
+                m_max,
+                m_max0,
+                32,
+                Metric::Euclidean,
+                ElementType::F64,
+                seed,
+            )
+            .unwrap();
+            for i in 1..2000 {
+                let top = g.layer_count - 1;
+                let id = g.insert(&random_vector(&mut rng, 8));
+
+                // only a node above the old top layer adds layers, and it becomes the entrence point
+                let level = g.nodes[id].borrow().max_level;
+                let ep = g.entrence_point.id().unwrap();
+                if level > top {
+                    assert_eq!((g.layer_count, ep), (level + 1, id));
+                } else {
+                    assert_eq!(g.layer_count, top + 1);
+                    assert_ne!(ep, id);
+                }
+                if i % 100 == 0 {
+                    let violations = check(&g);
+                    assert!(
+                        violations.is_empty(),
+                        "m {} after {} nodes: {}",
+                        m,
+                        g.nodes.len(),
+                        violations[0]
+                    );
+                }
+            }
+
+            // pruning can still cut off a node now and then, but not a noticeable share of them
+            let lost = unreachable(&g, g.entrence_point.id().unwrap()).len();
+            assert!(lost <= 2, "m {}: {} nodes unreachable", m, lost);
+
+            // levels are geometric, about 1 / m of the nodes on each layer are also on the next
+            let above = g.nodes.iter().filter(|n| n.borrow().max_level > 0).count();
+            let fraction = above as f64 / g.nodes.len() as f64;
+            assert!((fraction * m - 1.0).abs() < 0.35, "m {}: {}", m, fraction);
+        }
+
+        // a level doesn't depend on the layers already there, two nodes can be as tall as any
+        let mut tall = 0;
+        for seed in 0..2000 {
+            let mut g = Graph::new(
+                &[1.0, 0.0],
+                2.0,
+                4,
+                8,
+                16,
+                Metric::Euclidean,
+                ElementType::F64,
+                seed,
+            )
+            .unwrap();
+            g.insert(&[0.0, 1.0]);
+            tall += g.nodes.iter().filter(|n| n.borrow().max_level >= 2).count();
+        }
+        // with m 2 a quarter of the nodes reach layer 2
+        let fraction = tall as f64 / 4000.0;
+        assert!((fraction * 4.0 - 1.0).abs() < 0.2, "{}", fraction);
+    }
+
+    #[test]
+    fn budgets_cut_searches_short() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let g = random_graph(16, 2000, Metric::Euclidean, ElementType::F64, 0);
+        let q = random_vector(&mut rng, 16);
+        let ids =
+            |results: &[SearchResult]| -> Vec<usize> { results.iter().map(|r| r.id).collect() };
+
+        // a budget that doesn't run out changes nothing
+        let full = knn_search(&g, &q, 10, 64);
+        for budget in [
+            Budget::default(),
+            Budget {
+                distances: Some(1_000_000),
+                visited: Some(1_000_000),
+                time: Some(Duration::from_secs(60)),
+            },
+        ] {
+            let search = knn_search_budgeted(&g, &q, 10, 64, None, budget);
+            assert!(!search.partial);
+            assert_eq!(ids(&search.results), ids(&full));
+        }
+
+        // each kind of limit cuts the search short and still returns what it found, nearest first
+        let limits = [
+            Budget {
+                distances: Some(50),
+                ..Budget::default()
+            },
+            Budget {
+                visited: Some(3),
+                ..Budget::default()
+            },
+            Budget {
+                time: Some(Duration::ZERO),
+                ..Budget::default()
+            },
+        ];
+        for budget in limits {
+            let search = knn_search_budgeted(&g, &q, 10, 64, None, budget);
+            assert!(search.partial, "{:?}", budget);
+            assert!(!search.results.is_empty());
+            assert!(search
+                .results
+                .windows(2)
+                .all(|w| w[0].distance <= w[1].distance));
+        }
+
+        // filters that fall back to comparing every allowed node are limited by each kind as well
+        let allowed: IdSet = (0..20).collect();
+        for budget in [
+            Budget {
+                distances: Some(5),
+                ..Budget::default()
+            },
+            Budget {
+                visited: Some(5),
+                ..Budget::default()
+            },
+        ] {
+            let search = knn_search_budgeted(&g, &q, 10, 64, Some(&allowed), budget);
+            assert!(search.partial, "{:?}", budget);
+            assert_eq!(search.results.len(), 5);
+        }
+        let budget = Budget {
+            time: Some(Duration::ZERO),
+            ..Budget::default()
+        };
+        let search = knn_search_budgeted(&g, &q, 10, 64, Some(&allowed), budget);
+        assert!(search.partial);
+        assert!(search.results.is_empty());
+
+        let search = knn_search_budgeted(&g, &q, 10, 64, Some(&allowed), Budget::default());
+        assert!(!search.partial);
+        assert_eq!(
+            ids(&search.results),
+            ids(&brute_force_search_filtered(&g, &q, 10, &allowed))
+        );
+    }
+
+    #[test]
+    fn m_is_checked_and_levels_are_capped() {
+        for m in [1.0, 0.5, 0.0, -2.0, f64::NAN] {
+            let g = Graph::new(&[1.0], m, 4, 8, 16, Metric::Euclidean, ElementType::F64, 0);
+            assert!(g.is_err(), "{}", m);
+        }
+        // just above 1 nearly every level is drawn huge, none goes past the cap
+        let mut g = Graph::new(
+            &[1.0],
+            1.000001,
+            4,
+            8,
+            16,
+            Metric::Euclidean,
+            ElementType::F64,
+            0,
+        )
+        .unwrap();
+        for i in 0..20 {
+            g.insert(&[i as f64]);
+        }
+        assert!(g.nodes.iter().all(|n| n.borrow().max_level <= MAX_LEVEL));
+        assert_eq!(g.layer_count, MAX_LEVEL + 1);
+        assert!(check(&g).is_empty());
+    }
+
+    // share of `exact` (ids per query) that the search finds
+    fn recall_of(g: &Graph, queries: &[Vec<f64>], exact: &[Vec<usize>]) -> f64 {
+        let hits: usize = queries
+            .iter()
+            .zip(exact)
+            .map(|(q, exact)| {
+                let found = knn_search(g, q, exact.len(), 64);
+                exact
+                    .iter()
+                    .filter(|&&id| found.iter().any(|r| r.id == id))
+                    .count()
+            })
+            .sum();
+        hits as f64 / (10 * queries.len()) as f64
+    }
+
+    fn codes_fit(g: &Graph) -> bool {
+        !check(g)
+            .iter()
+            .any(|v| matches!(v, Violation::CodeMismatch { .. }))
+    }
+
+    #[test]
+    fn codes_only_graphs_drop_their_vectors() {
+        // centered on zero so the sign bits of binary codes mean something
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut centered = || -> Vec<f64> { (0..32).map(|_| rng.gen_range(-1.0..1.0)).collect() };
+        let vectors: Vec<Vec<f64>> = (0..500).map(|_| centered()).collect();
+        let queries: Vec<Vec<f64>> = (0..100).map(|_| centered()).collect();
+        // 32 bits can't tell much apart
+        for (name, min_recall) in [("int8", 0.9), ("pq", 0.5), ("binary", 0.25)] {
+            let mut g = Graph::new(
+                &vectors[0],
+                8.0,
+                8,
+                16,
+                64,
+                Metric::Cosine,
+                ElementType::F32,
+                1,
+            )
+            .unwrap();
+            vectors[1..].iter().for_each(|v| {
+                g.insert(v);
+            });
+            let exact: Vec<Vec<usize>> = queries
+                .iter()
+                .map(|q| brute_force_search(&g, q, 10).iter().map(|r| r.id).collect())
+                .collect();
+
+            // dropping needs codes for every node
+            assert!(g.drop_vectors().is_err());
+            let quantizer = match name {
+                "int8" => Quantizer::Scalar(ScalarQuantizer::train(&g.vectors())),
+                "pq" => {
+                    let pq = ProductQuantizer::new(32, 8, 64).unwrap();
+                    Quantizer::Product(pq.train(&g.vectors(), g.seed))
+                }
+                _ => Quantizer::Binary(BinaryQuantizer { rerank: true }),
+            };
+            g.quantize(Some(quantizer));
+            assert_eq!(GraphStats::new(&g, 0, None).vector_bytes, 500 * 32 * 4);
+            g.drop_vectors().unwrap();
+
+            // the vectors are gone, searches rank by the codes alone
+            assert!(g.nodes.iter().all(|n| n.borrow().vector.is_empty()));
+            assert_eq!(GraphStats::new(&g, 0, None).vector_bytes, 0);
+            assert!(codes_fit(&g), "{}", name);
+            let recall = recall_of(&g, &queries, &exact);
+            assert!(recall >= min_recall, "{} recall@10: {}", name, recall);
+            let brute = brute_force_search(&g, &queries[0], 10);
+            assert!(brute.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+            // vectors inserted later keep only their codes and can be found by them
+            let q = &queries[0];
+            g.insert(q);
+            let id = g.nodes.len() - 1;
+            assert!(g.nodes[id].borrow().vector.is_empty());
+            assert!(!g.nodes[id].borrow().codes.is_empty());
+            assert_eq!(knn_search(&g, q, 1, 64)[0].id, id, "{}", name);
+            assert_eq!(g.vector(id).len(), 32);
+            assert!(codes_fit(&g), "{}", name);
+
+            // quantizing again starts from the decoded vectors
+            g.quantize(None);
+            assert!(!g.codes_only);
+            assert!(g.nodes.iter().all(|n| n.borrow().vector.len() == 32));
+        }
+
+        // an untrained product quantizer has no codes to keep
+        let mut g = random_graph(32, 10, Metric::Euclidean, ElementType::F32, 1);
+        let pq = ProductQuantizer::new(32, 8, 64).unwrap();
+        g.quantize(Some(Quantizer::Product(pq)));
+        assert!(g.drop_vectors().is_err());
+        assert!(!g.codes_only);
+    }
+}
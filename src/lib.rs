@@ -0,0 +1,34 @@
+/*
+The graph, its file format and everything around them, shared by the `vite`
+command line and the language bindings.
+*/
+pub mod check;
+pub mod condition;
+pub mod file;
+pub mod filter;
+pub mod hnsw;
+pub mod metric;
+pub mod output;
+pub mod payload;
+pub mod quantize;
+pub mod simd;
+pub mod stats;
+pub mod vector;
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// each crate's tests use some of the fixtures
+#[cfg(test)]
+#[allow(dead_code)]
+mod testing;
+
+// lets `testing` name this crate the way the binary does
+#[cfg(test)]
+extern crate self as vite;
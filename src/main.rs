@@ -1,17 +1,54 @@
-use log::{debug, error, info, log_enabled, Level};
+use log::{debug, error, info, log_enabled, warn, Level};
 #[allow(dead_code)]
 #[allow(unused_variables)]
 #[allow(unused_must_use)]
 use rand::Rng;
+use std::collections::HashMap;
 use std::env;
 use std::io;
 use std::io::Write;
-
-mod hnsw;
-use crate::hnsw::{cosine_distance, knn_search, Graph};
-
-mod file;
-use crate::file::GraphFile;
+use std::time::Duration;
+
+use vite::check::{check, unreachable, Violation};
+use vite::condition::Condition;
+use vite::file::{table_size, write_collection, GraphFile, DEFAULT_COLLECTION};
+use vite::filter::{Filter, IdSet};
+use vite::hnsw::{
+    brute_force_search, brute_force_search_filtered, knn_search, knn_search_budgeted, Budget,
+    BudgetedSearch, Graph,
+};
+use vite::metric::Metric;
+use vite::output::{print_collections, print_document, print_node, print_search, Format};
+use vite::payload::Payload;
+use vite::quantize::{BinaryQuantizer, ProductQuantizer, Quantizer, ScalarQuantizer};
+use vite::stats::GraphStats;
+use vite::vector::ElementType;
+
+mod server;
+use crate::server::Server;
+
+// each crate's tests use some of the fixtures
+#[cfg(test)]
+#[allow(dead_code)]
+mod testing;
+
+#[cfg(feature = "grpc")]
+mod grpc;
+
+#[cfg(unix)]
+mod socket;
+
+// options that don't take a value
+const SWITCHES: [&str; 8] = [
+    "vectors",
+    "similarity",
+    "exact",
+    "binary",
+    "no-rerank",
+    "codes-only",
+    "upsert",
+    "grpc",
+];
 
 macro_rules! flush {
     () => {
@@ -21,15 +58,15 @@ macro_rules! flush {
 
 fn main() {
     env_logger::init();
-    let args: Vec<String> = env::args().collect();
+    let (args, options) = split_options(env::args().collect());
     if log_enabled!(Level::Debug) {
         debug!("started with the following {} arguments:", args.len());
         let mut buff = String::new();
-        buff.push_str("[");
+        buff.push('[');
         for arg in &args {
             buff.push_str(&format!("{}, ", arg))
         }
-        buff.push_str("]");
+        buff.push(']');
         debug!("{}", buff);
     }
 
@@ -38,52 +75,727 @@ fn main() {
         return;
     }
 
+    // every command works on one collection of the file
+    let collection = options
+        .get("collection")
+        .map_or(DEFAULT_COLLECTION, |c| c.as_str());
+
     // vite <filename>
     if args.len() == 2 {
         let filename = &args[1];
-        info!("opening: {}", filename);
-        let mut gf = GraphFile::open(filename.into());
-        let g = gf.read().unwrap();
+        info!("opening: {} {}", filename, collection);
+        let g = read_graph(filename, collection);
 
         g.print();
         interperter_loop(&g);
         return;
     }
 
+    let format = match Format::parse(options.get("format").map_or("text", |f| f.as_str())) {
+        Some(format) => format,
+        None => {
+            error!("format must be one of text, json, jsonl or tsv");
+            return;
+        }
+    };
+
     // vite <command> <filename>
-    let mut g = GraphFile::open(args[2].clone().into())
-        .read()
-        .expect("Could not read graph");
-    g.try_weaken_ep();
     match args[1].as_str() {
-        "new" => {}
-        "add" => {}
+        "new" => {
+            if args.len() < 8 {
+                error!("usage: new <filename> <initial vector> <m> <m_max> <m_max0> <candidate list size>");
+                return;
+            }
+            let metric = match Metric::parse(options.get("metric").map_or("cosine", |m| m.as_str()))
+            {
+                Some(metric) => metric,
+                None => {
+                    error!("metric must be one of cosine, l2 or dot");
+                    return;
+                }
+            };
+            let element_type =
+                match ElementType::parse(options.get("type").map_or("f64", |t| t.as_str())) {
+                    Some(element_type) => element_type,
+                    None => {
+                        error!("type must be one of f64, f32, f16 or bf16");
+                        return;
+                    }
+                };
+            let product = match product_params(&options) {
+                Ok(product) => product,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let binary = options.contains_key("binary").then(|| BinaryQuantizer {
+                rerank: !options.contains_key("no-rerank"),
+            });
+            // product quantizers are trained once the graph holds as many vectors as centroids
+            let quantizer = match (product, binary) {
+                (Some(_), Some(_)) => {
+                    error!("pick one of --subspaces or --binary");
+                    return;
+                }
+                (Some(product), None) => {
+                    match product_quantizer(parse_vector(&args[3]).len(), product) {
+                        Some(pq) => Some(Quantizer::Product(pq)),
+                        None => return,
+                    }
+                }
+                (None, binary) => binary.map(Quantizer::Binary),
+            };
+            // a random seed is still kept, so the graph can be rebuilt the same way
+            let seed = match options.get("seed").map(|seed| seed.parse()) {
+                Some(Ok(seed)) => seed,
+                Some(Err(_)) => {
+                    error!("seed must be a whole number");
+                    return;
+                }
+                None => rand::random(),
+            };
+            let node = match NodeOptions::new(&options) {
+                Ok(node) => node,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            new_graph(
+                &args[2],
+                collection,
+                &args[3..8],
+                metric,
+                element_type,
+                seed,
+                quantizer,
+                node,
+            )
+        }
+        "add" => {
+            if args.len() < 4 {
+                error!("usage: add <filename> <vector>");
+                return;
+            }
+            let node = match NodeOptions::new(&options) {
+                Ok(node) => node,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            add_vector(&args[2], collection, &args[3], node)
+        }
         "search" => {
+            if args.len() < 5 {
+                error!("usage: search <filename> <vector> <k>");
+                return;
+            }
             info!("search selected with: vector={} k={}", args[3], args[4]);
-            search_vector(&g, args[3].as_str(), args[4].as_str())
+            let search_options = match SearchOptions::new(&options, format) {
+                Ok(search_options) => search_options,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            search_vector(
+                &read_graph(&args[2], collection),
+                args[3].as_str(),
+                args[4].as_str(),
+                &search_options,
+            )
+        }
+        "get" => {
+            if args.len() < 4 {
+                error!("usage: get <filename> <key or id>");
+                return;
+            }
+            get_node(&args[2], collection, &args[3], format)
+        }
+        "delete" => {
+            if args.len() < 4 {
+                error!("usage: delete <filename> <key or id>");
+                return;
+            }
+            delete_node(&args[2], collection, &args[3])
+        }
+        "quantize" => {
+            if args.len() < 4 {
+                error!("usage: quantize <filename> <int8|pq|binary|none> [--codes-only]");
+                return;
+            }
+            let product = match product_params(&options) {
+                Ok(product) => product,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            quantize_graph(
+                &args[2],
+                collection,
+                &args[3],
+                product,
+                !options.contains_key("no-rerank"),
+                options.contains_key("codes-only"),
+            )
+        }
+        "check" => check_graph(&args[2], collection),
+        "info" => info_graph(&args[2], collection, format),
+        "collections" => list_collections(&args[2], format),
+        "serve" => {
+            let host = options.get("host").map_or("127.0.0.1", |h| h.as_str());
+            if let Some(path) = options.get("socket") {
+                serve_socket(&args[2], collection, path)
+            } else if options.contains_key("grpc") {
+                let port = options.get("port").map_or("50051", |p| p.as_str());
+                serve_grpc(&args[2], collection, &format!("{}:{}", host, port))
+            } else {
+                let port = options.get("port").map_or("8080", |p| p.as_str());
+                serve_graph(&args[2], collection, &format!("{}:{}", host, port))
+            }
         }
         _ => error!("invalid command"),
     }
 }
 
+// pulls `--name value` options and `--switch`es out of the arguments
+fn split_options(args: Vec<String>) -> (Vec<String>, HashMap<String, String>) {
+    let mut positional = Vec::new();
+    let mut options = HashMap::new();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.strip_prefix("--") {
+            Some(name) if SWITCHES.contains(&name) => {
+                options.insert(name.to_string(), String::new());
+            }
+            Some(name) => {
+                options.insert(name.to_string(), iter.next().unwrap_or_default());
+            }
+            None => positional.push(arg),
+        }
+    }
+    (positional, options)
+}
+
+// what `new` and `add` take besides the vector
+struct NodeOptions {
+    // `--payload '{"title": "...", "tags": ["a", "b"]}'`, empty when not given
+    payload: Payload,
+    // `--key <key>`
+    key: Option<String>,
+    // `--upsert` replaces the node that has the key instead of failing
+    upsert: bool,
+}
+
+impl NodeOptions {
+    fn new(options: &HashMap<String, String>) -> Result<Self, String> {
+        let payload = match options.get("payload") {
+            Some(json) => {
+                let value = serde_json::from_str(json).map_err(|e| format!("payload: {}", e))?;
+                Payload::from_json(&value)?
+            }
+            None => Payload::default(),
+        };
+        Ok(NodeOptions {
+            payload,
+            key: options.get("key").cloned(),
+            upsert: options.contains_key("upsert"),
+        })
+    }
+}
+
+// keys win over ids, so numeric keys still work
+fn resolve_node(g: &Graph, key_or_id: &str) -> Option<usize> {
+    g.id_of(key_or_id).or_else(|| {
+        key_or_id
+            .parse::<usize>()
+            .ok()
+            .filter(|&id| id < g.nodes.len() && !g.nodes[id].borrow().deleted)
+    })
+}
+
+// `--subspaces <n> [--centroids <k>]`, centroids default to 256
+fn product_params(options: &HashMap<String, String>) -> Result<Option<(usize, usize)>, String> {
+    let subspaces = match options.get("subspaces") {
+        Some(subspaces) => subspaces
+            .parse()
+            .map_err(|_| "subspaces must be a number")?,
+        None => return Ok(None),
+    };
+    let centroids = match options.get("centroids") {
+        Some(centroids) => centroids
+            .parse()
+            .map_err(|_| "centroids must be a number")?,
+        None => 256,
+    };
+    Ok(Some((subspaces, centroids)))
+}
+
+fn product_quantizer(
+    dimension: usize,
+    (subspaces, centroids): (usize, usize),
+) -> Option<ProductQuantizer> {
+    let pq = ProductQuantizer::new(dimension, subspaces, centroids);
+    if pq.is_none() {
+        error!(
+            "subspaces must divide the dimension {} and centroids must be between 1 and 256",
+            dimension
+        );
+    }
+    pq
+}
+
 /*
 fn new_graph_wizard() -> Graph {
 
 }
 */
 
-fn search_vector(g: &Graph, q_str: &str, k_str: &str) {
+// `vite new foo` writes foo.vlite
+fn graph_path(filename: &str) -> String {
+    if std::path::Path::new(filename).extension().is_some() {
+        filename.to_string()
+    } else {
+        format!("{}.vlite", filename)
+    }
+}
+
+fn read_graph(filename: &str, collection: &str) -> Box<Graph> {
+    let mut g = match open_graph(filename).and_then(|mut file| file.read_collection(collection)) {
+        Ok(g) => g,
+        Err(e) => {
+            error!("could not read {}: {}", graph_path(filename), e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = g.try_weaken_ep().and_then(|()| g.try_link_friends()) {
+        error!("could not read {}: {}", graph_path(filename), e);
+        std::process::exit(1);
+    }
+    g
+}
+
+fn open_graph(filename: &str) -> io::Result<GraphFile> {
+    GraphFile::open(graph_path(filename))
+}
+
+// replaces `collection` in the file, or adds it, and keeps the other collections
+fn write_graph(filename: &str, collection: &str, g: &Graph) {
+    write_collection(&graph_path(filename), collection, g).expect("Could not write graph");
+}
+
+#[allow(clippy::too_many_arguments)]
+fn new_graph(
+    filename: &str,
+    collection: &str,
+    params: &[String],
+    metric: Metric,
+    element_type: ElementType,
+    seed: u64,
+    quantizer: Option<Quantizer>,
+    node: NodeOptions,
+) {
+    let q = parse_vector(&params[0]);
+    let (Ok(m), Ok(m_max), Ok(m_max0), Ok(candidate_list_size)) = (
+        params[1].parse::<f64>(),
+        params[2].parse::<usize>(),
+        params[3].parse::<usize>(),
+        params[4].parse::<usize>(),
+    ) else {
+        error!("m, m_max, m_max0 and the candidate list size must be numbers");
+        return;
+    };
+
+    let mut g = match Graph::new(
+        &q,
+        m,
+        m_max,
+        m_max0,
+        candidate_list_size,
+        metric,
+        element_type,
+        seed,
+    ) {
+        Ok(g) => g,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if quantizer.is_some() {
+        g.quantize(quantizer);
+    }
+    g.nodes[0].borrow_mut().payload = node.payload;
+    if let Some(key) = node.key {
+        g.nodes[0].borrow_mut().key = Some(key.clone());
+        g.keys.insert(key, 0);
+    }
+    write_graph(filename, collection, &g);
+}
+
+fn add_vector(filename: &str, collection: &str, q_str: &str, node: NodeOptions) {
+    let mut g = read_graph(filename, collection);
     let q = parse_vector(q_str);
-    let k = u64::from_str_radix(k_str, 10).unwrap();
-    let search = knn_search(&g, &q, k.try_into().unwrap(), 20);
+    if q.len() != g.dimension {
+        error!("expected a vector of length {}", g.dimension);
+        return;
+    }
+
+    let key = node.key.clone();
+    if let Err(existing) = insert_node(&mut g, &q, node) {
+        error!(
+            "key {} is already node {}, use --upsert to replace it",
+            key.unwrap_or_default(),
+            existing
+        );
+        std::process::exit(1);
+    }
+    write_graph(filename, collection, &g);
+}
 
-    for elem in search {
-        print!("{}, ", elem.borrow().index)
+// inserts `q` with the options' key and payload, fails with the node that already has the key
+fn insert_node(g: &mut Graph, q: &[f64], node: NodeOptions) -> Result<usize, usize> {
+    let id = match &node.key {
+        Some(key) => g.insert_with_key(q, key, node.upsert)?,
+        None => g.insert(q),
+    };
+    g.nodes[id].borrow_mut().payload = node.payload;
+    Ok(id)
+}
+
+fn quantize_graph(
+    filename: &str,
+    collection: &str,
+    kind: &str,
+    product: Option<(usize, usize)>,
+    rerank: bool,
+    codes_only: bool,
+) {
+    let mut g = read_graph(filename, collection);
+    let quantizer = match (kind, product) {
+        ("int8", _) => Some(Quantizer::Scalar(ScalarQuantizer::train(&g.vectors()))),
+        ("pq", Some(product)) => match product_quantizer(g.dimension, product) {
+            Some(pq) => Some(Quantizer::Product(pq.train(&g.vectors(), g.seed))),
+            None => return,
+        },
+        ("pq", None) => {
+            error!("pq needs --subspaces <n>");
+            return;
+        }
+        ("binary", _) => Some(Quantizer::Binary(BinaryQuantizer { rerank })),
+        ("none", _) => None,
+        _ => {
+            error!("quantizer must be one of int8, pq, binary or none");
+            return;
+        }
+    };
+
+    g.quantize(quantizer);
+    if codes_only {
+        if let Err(e) = g.drop_vectors() {
+            error!("{}", e);
+            return;
+        }
     }
+    write_graph(filename, collection, &g);
+}
+
+fn check_graph(filename: &str, collection: &str) {
+    // friends are left as indices so dangling ids still show up
+    let read = open_graph(filename).and_then(|mut file| file.read_collection(collection));
+    let violations = match &read {
+        Ok(g) => check(g),
+        Err(e) => vec![Violation::Unreadable {
+            error: e.to_string(),
+        }],
+    };
+    for violation in &violations {
+        println!("{}", violation);
+    }
+    if !violations.is_empty() {
+        println!("{} problems found", violations.len());
+        std::process::exit(1);
+    }
+    if let Ok(g) = read {
+        // check has made sure the entrence point is there
+        let ep = g.entrence_point.id().unwrap();
+        for node in unreachable(&g, ep) {
+            println!(
+                "warning: node {} is unreachable from the entrence point",
+                node
+            );
+        }
+        println!("ok: {} nodes, {} layers", g.nodes.len(), g.layer_count);
+    }
+}
+
+fn info_graph(filename: &str, collection: &str, format: Format) {
+    let path = graph_path(filename);
+    let mut file = open_graph(filename).expect("Could not read graph");
+    let g = file
+        .read_collection(collection)
+        .expect("Could not read graph");
+    let table = file.table().expect("Could not read graph");
+    let names: Vec<&str> = table.iter().map(|entry| entry.name.as_str()).collect();
+    let file_bytes = std::fs::metadata(&path).ok().map(|m| m.len());
+
+    let stats = GraphStats::new(&g, table_size(&names), file_bytes);
+    print_document(format, &stats, &stats.to_json());
+}
+
+fn list_collections(filename: &str, format: Format) {
+    let collections = open_graph(filename)
+        .and_then(|mut file| file.read_all())
+        .expect("Could not read graph");
+    print_collections(format, &collections);
+}
+
+fn serve_graph(filename: &str, collection: &str, address: &str) {
+    let g = read_graph(filename, collection);
+    let listener = match std::net::TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("could not listen on {}: {}", address, e);
+            std::process::exit(1);
+        }
+    };
+    info!("serving {} {} on {}", filename, collection, address);
+    Server::new(filename, collection, g).serve(listener);
+}
+
+#[cfg(unix)]
+fn serve_socket(filename: &str, collection: &str, path: &str) {
+    let g = read_graph(filename, collection);
+    info!("serving {} {} on {}", filename, collection, path);
+    if let Err(e) = socket::serve(&g, path) {
+        error!("could not listen on {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_filename: &str, _collection: &str, _path: &str) {
+    error!("unix sockets are only available on unix");
+    std::process::exit(1);
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(filename: &str, collection: &str, address: &str) {
+    info!(
+        "serving {} {} over grpc on {}",
+        filename, collection, address
+    );
+    if let Err(e) = grpc::serve(filename, collection, address) {
+        error!("grpc server failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_filename: &str, _collection: &str, _address: &str) {
+    error!("built without grpc, rebuild with --features grpc");
+    std::process::exit(1);
+}
+
+fn get_node(filename: &str, collection: &str, key_or_id: &str, format: Format) {
+    let g = read_graph(filename, collection);
+    match resolve_node(&g, key_or_id) {
+        Some(id) => print_node(format, &g, id),
+        None => {
+            error!("no node {}", key_or_id);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn delete_node(filename: &str, collection: &str, key_or_id: &str) {
+    let mut g = read_graph(filename, collection);
+    match resolve_node(&g, key_or_id) {
+        Some(id) => {
+            g.delete(id);
+            write_graph(filename, collection, &g);
+        }
+        None => {
+            error!("no node {}", key_or_id);
+            std::process::exit(1);
+        }
+    }
+}
+
+// everything `search` takes besides the vector and k
+struct SearchOptions {
+    format: Format,
+    vectors: bool,
+    similarity: bool,
+    exact: bool,
+    // only return these ids, as many as were asked for so ids past the end can't size the set
+    ids: Option<Vec<usize>>,
+    // only return nodes whose payload matches
+    condition: Option<Condition>,
+    // ignored by exact searches
+    budget: Budget,
+}
+
+impl SearchOptions {
+    fn new(options: &HashMap<String, String>, format: Format) -> Result<Self, String> {
+        let ids = match options.get("ids") {
+            Some(ids) => Some(parse_list::<usize>(ids).ok_or("ids must be a list of numbers")?),
+            None => None,
+        };
+        let condition = match options.get("where") {
+            Some(condition) => Some(Condition::parse(condition)?),
+            None => None,
+        };
+        let limit = |name: &str| match options.get(name) {
+            Some(limit) => limit
+                .parse::<usize>()
+                .map(Some)
+                .map_err(|_| format!("{} must be a whole number", name)),
+            None => Ok(None),
+        };
+        let budget = Budget {
+            distances: limit("max-distances")?,
+            visited: limit("max-visited")?,
+            time: limit("timeout")?.map(|ms| Duration::from_millis(ms as u64)),
+        };
+        Ok(SearchOptions {
+            format,
+            vectors: options.contains_key("vectors"),
+            similarity: options.contains_key("similarity"),
+            exact: options.contains_key("exact"),
+            ids,
+            condition,
+            budget,
+        })
+    }
+
+    // what the interpreter searches with, plain results and an optional condition
+    fn interactive(condition: Option<Condition>) -> Self {
+        SearchOptions {
+            format: Format::Text,
+            vectors: false,
+            similarity: false,
+            exact: false,
+            ids: None,
+            condition,
+            budget: Budget::default(),
+        }
+    }
+}
+
+fn search_graph(g: &Graph, q: &[f64], k: usize, options: &SearchOptions) -> BudgetedSearch {
+    // ids past the last node can't match anything, the set only has room for the graph
+    let ids: Option<IdSet> = options.ids.as_ref().map(|ids| {
+        ids.iter()
+            .copied()
+            .filter(|&id| id < g.nodes.len())
+            .collect()
+    });
+    let ids = ids.as_ref();
+    let condition = options.condition.as_ref().map(|c| c.filter(g));
+    let filter = |id: usize| {
+        ids.is_none_or(|ids| ids.contains(id)) && condition.as_ref().is_none_or(|c| c.allows(id))
+    };
+    let filter: Option<&dyn Filter> = (ids.is_some() || condition.is_some()).then_some(&filter);
+
+    if options.exact {
+        let results = match filter {
+            Some(filter) => brute_force_search_filtered(g, q, k, filter),
+            None => brute_force_search(g, q, k),
+        };
+        return BudgetedSearch {
+            results,
+            partial: false,
+        };
+    }
+    knn_search_budgeted(g, q, k, 20, filter, options.budget)
+}
+
+fn search_vector(g: &Graph, q_str: &str, k_str: &str, options: &SearchOptions) {
+    let q = parse_vector(q_str);
+    if q.len() != g.dimension {
+        error!("expected a vector of length {}", g.dimension);
+        std::process::exit(1);
+    }
+    let k: usize = match k_str.parse() {
+        Ok(k) => k,
+        Err(_) => {
+            error!("k must be a number");
+            std::process::exit(1);
+        }
+    };
+    let search = search_graph(g, &q, k, options);
+    if search.partial {
+        warn!("the search ran out of budget, the results are the best found so far");
+    }
+
+    print_search(
+        options.format,
+        g,
+        &search.results,
+        options.vectors,
+        options.similarity,
+    );
+}
+
+// accepts "1,2,3", "1 2 3" or "[1, 2, 3]"
+fn parse_list<T: std::str::FromStr>(string: &str) -> Option<Vec<T>> {
+    string
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().ok())
+        .collect()
 }
 
 fn parse_vector(string: &str) -> Vec<f64> {
-    return vec![1.0, 2.0, 3.0, 4.0];
+    match parse_list(string) {
+        Some(vector) => vector,
+        None => {
+            error!("vector components must be numbers");
+            std::process::exit(1);
+        }
+    }
+}
+
+/*
+commands:
+    search <vector> <k> [where <condition>]
+    get <key or id>
+    help
+    quit
+vectors are written like on the command line, "1,2,3" or "[1, 2, 3]"
+*/
+enum Command {
+    Search(Vec<f64>, usize, Option<Condition>),
+    Get(String),
+    Help,
+    Quit,
+    Nothing,
+}
+
+const HELP: [&str; 3] = [
+    "search <vector> <k> [where <condition>]",
+    "get <key or id>",
+    "quit",
+];
+
+impl Command {
+    fn parse(g: &Graph, line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "" => Ok(Command::Nothing),
+            "search" => parse_search(g, rest.trim()),
+            "get" => Ok(Command::Get(rest.trim().to_string())),
+            "help" => Ok(Command::Help),
+            "quit" | "exit" => Ok(Command::Quit),
+            _ => Err(format!("unknown command {}, try help", command)),
+        }
+    }
 }
 
 fn interperter_loop(g: &Graph) {
@@ -93,9 +805,23 @@ fn interperter_loop(g: &Graph) {
     {
         while let Ok(n_bytes) = io::stdin().read_line(&mut input) {
             if n_bytes == 0 {
-                continue;
+                break;
+            }
+            match Command::parse(g, &input) {
+                Ok(Command::Search(q, k, condition)) => {
+                    let options = SearchOptions::interactive(condition);
+                    let search = search_graph(g, &q, k, &options);
+                    print_search(Format::Text, g, &search.results, false, false);
+                }
+                Ok(Command::Get(key_or_id)) => match resolve_node(g, &key_or_id) {
+                    Some(id) => print_node(Format::Text, g, id),
+                    None => println!("error: no node {}", key_or_id),
+                },
+                Ok(Command::Help) => HELP.iter().for_each(|line| println!("{}", line)),
+                Ok(Command::Quit) => break,
+                Ok(Command::Nothing) => {}
+                Err(e) => println!("error: {}", e),
             }
-            println!("*{}", input.trim());
             input.clear();
             print!("> ");
             flush!();
@@ -103,29 +829,59 @@ fn interperter_loop(g: &Graph) {
     }
 }
 
+fn parse_search(g: &Graph, args: &str) -> Result<Command, String> {
+    // a bracketed vector may contain spaces
+    let (vector, rest) = match args.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').ok_or("expected ]")?,
+        None => args.split_once(' ').unwrap_or((args, "")),
+    };
+    let q: Vec<f64> = parse_list(vector).ok_or("vector components must be numbers")?;
+    if q.len() != g.dimension {
+        return Err(format!("expected a vector of length {}", g.dimension));
+    }
+
+    let rest = rest.trim();
+    let (k, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    let k: usize = k.parse().map_err(|_| "k must be a number")?;
+
+    let rest = rest.trim();
+    let condition = if rest.is_empty() {
+        None
+    } else {
+        match rest.split_once(' ') {
+            Some((w, condition)) if w.eq_ignore_ascii_case("where") => {
+                Some(Condition::parse(condition)?)
+            }
+            _ => return Err(format!("expected where, found {}", rest)),
+        }
+    };
+    Ok(Command::Search(q, k, condition))
+}
+
 fn _test_search(g: &Graph) {
     println!("lets try a search!");
     //let vec: [f64; 4] = [rng.gen(), rng.gen(), rng.gen(), rng.gen()];
     //let vec = g.nodes.last().unwrap().borrow().vector.clone();
     //let vec: [f64; 4] = [1.0, 2.0, 3.0, 4.0];
-    let vec = g.nodes.first().unwrap().borrow().vector.clone();
+    let vec = g.nodes.first().unwrap().borrow().vector.to_f64();
 
     print!("[");
     vec.iter().for_each(|x| print!("{}, ", x));
-    print!("]\n");
+    println!("]");
 
     println!("\nthinking...");
-    let search = knn_search(&g, &vec, 5, 20);
+    let search = knn_search(g, &vec, 5, 20);
 
     search.iter().for_each(|x| {
-        print!(
-            "{}, {}: ",
-            x.borrow().index,
-            cosine_distance(&vec, &x.borrow().vector)
-        );
+        print!("{}, {}: ", x.id, x.distance);
         print!("[");
-        x.borrow().vector.iter().for_each(|v| print!("{v}, "));
-        print!("]\n");
+        g.nodes[x.id]
+            .borrow()
+            .vector
+            .to_f64()
+            .iter()
+            .for_each(|v| print!("{v}, "));
+        println!("]");
     });
 
     println!("\n");
@@ -146,11 +902,11 @@ fn _test_write_read(g: &mut Graph) {
     _test_graph(g);
     g.print();
     println!("writing to disk...");
-    let mut gf = GraphFile::create("test.vite".into());
-    gf.write(&g).unwrap();
+    let mut gf = GraphFile::create("test.vite".into()).unwrap();
+    gf.write_all(&[(DEFAULT_COLLECTION, g)]).unwrap();
 
-    let mut gf_new = GraphFile::open("test.vite".into());
-    let g_new = gf_new.read().unwrap();
+    let mut gf_new = GraphFile::open("test.vite".into()).unwrap();
+    let g_new = gf_new.read_collection(DEFAULT_COLLECTION).unwrap();
 
     g_new.print();
 }
@@ -158,11 +914,11 @@ fn _test_write_read(g: &mut Graph) {
 fn _test_file(g: &mut Graph) {
     g.print();
     println!("trying file writing....");
-    let mut gf = GraphFile::create("test.vite".into());
-    gf.write(&g).unwrap();
+    let mut gf = GraphFile::create("test.vite".into()).unwrap();
+    gf.write_all(&[(DEFAULT_COLLECTION, g)]).unwrap();
 
-    let mut new_gf = GraphFile::open("test.vite".into());
-    let _graph_bytes = new_gf.read().unwrap();
+    let mut new_gf = GraphFile::open("test.vite".into()).unwrap();
+    let _graph_bytes = new_gf.read_collection(DEFAULT_COLLECTION).unwrap();
     //let new_grah = Graph::deserialize(&graph_bytes.as_ref().try_into().unwrap());
     //new_grah.print();
 }
@@ -183,24 +939,25 @@ fn _test_graph(g: &mut Graph) {
     //let vec: [f64; 4] = [rng.gen(), rng.gen(), rng.gen(), rng.gen()];
     //let vec = g.nodes.last().unwrap().borrow().vector.clone();
     //let vec: [f64; 4] = [1.0, 2.0, 3.0, 4.0];
-    let vec = g.nodes.first().unwrap().borrow().vector.clone();
+    let vec = g.nodes.first().unwrap().borrow().vector.to_f64();
 
     print!("[");
     vec.iter().for_each(|x| print!("{}, ", x));
-    print!("]\n");
+    println!("]");
 
     println!("\nthinking...");
-    let search = knn_search(&g, &vec, 5, 20);
+    let search = knn_search(g, &vec, 5, 20);
 
     search.iter().for_each(|x| {
-        print!(
-            "{}, {}: ",
-            x.borrow().index,
-            cosine_distance(&vec, &x.borrow().vector)
-        );
+        print!("{}, {}: ", x.id, x.distance);
         print!("[");
-        x.borrow().vector.iter().for_each(|v| print!("{v}, "));
-        print!("]\n");
+        g.nodes[x.id]
+            .borrow()
+            .vector
+            .to_f64()
+            .iter()
+            .for_each(|v| print!("{v}, "));
+        println!("]");
     });
 
     println!("\n");
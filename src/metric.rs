@@ -0,0 +1,139 @@
+use crate::vector::{Element, Vector};
+
+/*
+Distance functions a graph can be built with. Smaller distances are always nearer,
+`similarity` turns a distance back into a score where bigger is nearer.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Metric {
+    // 1 - cos(a, b), similarity is cos(a, b). 1 when either is the zero vector
+    Cosine,
+    // |a - b|, similarity is 1 / (1 + |a - b|)
+    Euclidean,
+    // -(a . b), similarity is a . b
+    Dot,
+}
+
+impl Metric {
+    // `a` and `b` should share an element type, mixed vectors are widened to f64 first
+    pub fn distance(&self, a: &Vector, b: &Vector) -> f64 {
+        match (a, b) {
+            (Vector::F64(a), Vector::F64(b)) => self.distance_slice(a, b),
+            (Vector::F32(a), Vector::F32(b)) => self.distance_slice(a, b),
+            (Vector::F16(a), Vector::F16(b)) => self.distance_slice(a, b),
+            (Vector::BF16(a), Vector::BF16(b)) => self.distance_slice(a, b),
+            _ => self.distance_slice(&a.to_f64(), &b.to_f64()),
+        }
+    }
+
+    // same as `distance`, but cosine uses the norms it is given instead of working them out
+    pub fn distance_with_norms(&self, a: &Vector, a_norm: f64, b: &Vector, b_norm: f64) -> f64 {
+        match self {
+            Metric::Cosine => {
+                let dot = match (a, b) {
+                    (Vector::F64(a), Vector::F64(b)) => dot(a, b),
+                    (Vector::F32(a), Vector::F32(b)) => dot(a, b),
+                    (Vector::F16(a), Vector::F16(b)) => dot(a, b),
+                    (Vector::BF16(a), Vector::BF16(b)) => dot(a, b),
+                    _ => dot(&a.to_f64(), &b.to_f64()),
+                };
+                cosine_from_parts(dot, a_norm, b_norm)
+            }
+            _ => self.distance(a, b),
+        }
+    }
+
+    pub fn distance_slice<T: Element>(&self, a: &[T], b: &[T]) -> f64 {
+        match self {
+            Metric::Cosine => cosine_distance(a, b),
+            Metric::Euclidean => euclidean_distance(a, b),
+            Metric::Dot => -dot(a, b),
+        }
+    }
+
+    pub fn similarity(&self, distance: f64) -> f64 {
+        match self {
+            Metric::Cosine => 1.0 - distance,
+            Metric::Euclidean => 1.0 / (1.0 + distance),
+            Metric::Dot => -distance,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Metric> {
+        match s {
+            "cosine" => Some(Metric::Cosine),
+            "l2" | "euclidean" => Some(Metric::Euclidean),
+            "dot" => Some(Metric::Dot),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Cosine => "cosine",
+            Metric::Euclidean => "l2",
+            Metric::Dot => "dot",
+        }
+    }
+
+    // how the metric is stored in the file header
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Metric::Cosine => 0,
+            Metric::Euclidean => 1,
+            Metric::Dot => 2,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Option<Metric> {
+        match b {
+            0 => Some(Metric::Cosine),
+            1 => Some(Metric::Euclidean),
+            2 => Some(Metric::Dot),
+            _ => None,
+        }
+    }
+}
+
+pub fn dot<T: Element>(a: &[T], b: &[T]) -> f64 {
+    T::dot(a, b)
+}
+
+pub fn euclidean_distance<T: Element>(a: &[T], b: &[T]) -> f64 {
+    T::l2_squared(a, b).sqrt()
+}
+
+// both norms come out of the same pass as the dot product
+pub fn cosine_distance<T: Element>(a: &[T], b: &[T]) -> f64 {
+    let (num, aa, bb) = T::cosine_parts(a, b);
+    cosine_from_parts(num, aa.sqrt(), bb.sqrt())
+}
+
+// a zero vector has no direction, it is as far from everything as an orthogonal one
+pub fn cosine_from_parts(dot: f64, a_norm: f64, b_norm: f64) -> f64 {
+    if a_norm == 0.0 || b_norm == 0.0 {
+        return 1.0;
+    }
+    1.0 - dot / (a_norm * b_norm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_round_trip() {
+        for metric in [Metric::Cosine, Metric::Euclidean, Metric::Dot] {
+            assert_eq!(Metric::parse(metric.name()), Some(metric));
+            assert_eq!(Metric::from_byte(metric.to_byte()), Some(metric));
+        }
+        assert_eq!(Metric::from_byte(3), None);
+
+        let (a, b) = ([3.0, 4.0], [6.0, 8.0]);
+        assert!(Metric::Cosine.distance_slice(&a, &b).abs() < 1e-12);
+        assert_eq!(Metric::Euclidean.distance_slice(&a, &b), 5.0);
+        assert_eq!(Metric::Dot.distance_slice(&a, &b), -50.0);
+        assert_eq!(Metric::Euclidean.similarity(5.0), 1.0 / 6.0);
+        assert_eq!(Metric::Dot.similarity(-50.0), 50.0);
+    }
+}
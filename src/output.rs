@@ -0,0 +1,263 @@
+use serde_json::{json, Value};
+
+use crate::file::Entry;
+use crate::hnsw::{Graph, SearchResult};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    // pretty printed document
+    Json,
+    // one compact object per line
+    Jsonl,
+    // tab separated, vectors are comma separated inside a column
+    Tsv,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "jsonl" => Some(Format::Jsonl),
+            "tsv" => Some(Format::Tsv),
+            _ => None,
+        }
+    }
+}
+
+fn join_vector(v: &[f64]) -> String {
+    v.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+pub fn print_search(
+    format: Format,
+    g: &Graph,
+    results: &[SearchResult],
+    vectors: bool,
+    similarity: bool,
+) {
+    let rows = search_rows(g, results, vectors, similarity);
+
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&rows).unwrap()),
+        Format::Jsonl => rows.iter().for_each(|row| println!("{}", row)),
+        Format::Text | Format::Tsv => {
+            let sep = if format == Format::Tsv { "\t" } else { " " };
+            for (row, r) in rows.iter().zip(results) {
+                print!("{}", row["id"]);
+                if let Some(key) = row.get("key").and_then(|key| key.as_str()) {
+                    print!("{}{}", sep, key);
+                }
+                print!("{}{}", sep, row["distance"]);
+                if similarity {
+                    print!("{}{}", sep, row["similarity"]);
+                }
+                if vectors {
+                    print!("{}{}", sep, join_vector(&g.vector(r.id)));
+                }
+                if let Some(payload) = row.get("payload") {
+                    print!("{}{}", sep, payload);
+                }
+                println!();
+            }
+        }
+    }
+}
+
+// one json object per result, what `--format json` prints
+pub fn search_rows(
+    g: &Graph,
+    results: &[SearchResult],
+    vectors: bool,
+    similarity: bool,
+) -> Vec<Value> {
+    results
+        .iter()
+        .map(|r| {
+            let mut row = json!({
+                "id": r.id,
+                "distance": r.distance,
+            });
+            if similarity {
+                row["similarity"] = json!(r.similarity(g.metric));
+            }
+            if vectors {
+                row["vector"] = json!(g.vector(r.id));
+            }
+            let node = g.nodes[r.id].borrow();
+            if let Some(key) = &node.key {
+                row["key"] = json!(key);
+            }
+            if !node.payload.is_empty() {
+                row["payload"] = node.payload.to_json();
+            }
+            row
+        })
+        .collect()
+}
+
+pub fn print_node(format: Format, g: &Graph, id: usize) {
+    let n = g.nodes[id].borrow();
+    let vector = g.vector(id);
+
+    match format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&node_json(g, id)).unwrap()
+        ),
+        Format::Jsonl => println!("{}", node_json(g, id)),
+        Format::Tsv => {
+            print!("{}\t{}\t{}", n.index, n.max_level, join_vector(&vector));
+            if !n.payload.is_empty() {
+                print!("\t{}", n.payload.to_json());
+            }
+            println!();
+        }
+        Format::Text => {
+            println!("id:\t{}", n.index);
+            if let Some(key) = &n.key {
+                println!("key:\t{}", key);
+            }
+            println!("level:\t{}", n.max_level);
+            println!("vector:\t[{}]", join_vector(&vector).replace(',', ", "));
+            for (layer, friends) in friends(g, id).iter().enumerate() {
+                println!("friends {}:\t{:?}", layer, friends);
+            }
+            if !n.payload.is_empty() {
+                println!("payload:\t{}", n.payload.to_json());
+            }
+        }
+    }
+}
+
+pub fn node_json(g: &Graph, id: usize) -> Value {
+    let n = g.nodes[id].borrow();
+    let mut value = json!({
+        "id": n.index,
+        "level": n.max_level,
+        "vector": g.vector(id),
+        "friends": friends(g, id),
+    });
+    if let Some(key) = &n.key {
+        value["key"] = json!(key);
+    }
+    if n.deleted {
+        value["deleted"] = json!(true);
+    }
+    if !n.payload.is_empty() {
+        value["payload"] = n.payload.to_json();
+    }
+    value
+}
+
+// friend ids, layer by layer
+fn friends(g: &Graph, id: usize) -> Vec<Vec<usize>> {
+    g.nodes[id]
+        .borrow()
+        .friend_layers
+        .iter()
+        .map(|l| l.iter().map(|f| f.id()).collect())
+        .collect()
+}
+
+// nested objects become dotted keys, one `key\tvalue` per line
+fn print_flat(prefix: &str, value: &Value) {
+    for (key, value) in flatten(prefix, value) {
+        println!("{}\t{}", key, value);
+    }
+}
+
+fn flatten(prefix: &str, value: &Value) -> Vec<(String, Value)> {
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .flat_map(|(k, v)| {
+                let key = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                flatten(&key, v)
+            })
+            .collect(),
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .flat_map(|(i, v)| flatten(&format!("{}.{}", prefix, i), v))
+            .collect(),
+        _ => vec![(prefix.to_string(), value.clone())],
+    }
+}
+
+// one line per collection: name, nodes, dimension, metric, element type and size in the file
+pub fn print_collections(format: Format, collections: &[(Entry, Box<Graph>)]) {
+    let rows: Vec<Value> = collections
+        .iter()
+        .map(|(entry, g)| {
+            json!({
+                "name": entry.name,
+                "nodes": g.nodes.len(),
+                "dimension": g.dimension,
+                "metric": g.metric.name(),
+                "element_type": g.element_type.name(),
+                "bytes": entry.size,
+            })
+        })
+        .collect();
+
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&rows).unwrap()),
+        Format::Jsonl => rows.iter().for_each(|row| println!("{}", row)),
+        Format::Text | Format::Tsv => {
+            let sep = if format == Format::Tsv { "\t" } else { " " };
+            for (entry, g) in collections {
+                println!(
+                    "{}{sep}{}{sep}{}{sep}{}{sep}{}{sep}{}",
+                    entry.name,
+                    g.nodes.len(),
+                    g.dimension,
+                    g.metric.name(),
+                    g.element_type.name(),
+                    entry.size,
+                );
+            }
+        }
+    }
+}
+
+// anything that already has a text rendering and a json one, like `GraphStats`
+pub fn print_document(format: Format, text: &dyn std::fmt::Display, value: &Value) {
+    match format {
+        Format::Text => print!("{}", text),
+        Format::Json => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+        Format::Jsonl => println!("{}", value),
+        Format::Tsv => print_flat("", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_flattening() {
+        for name in ["text", "json", "jsonl", "tsv"] {
+            assert!(Format::parse(name).is_some());
+        }
+        assert!(Format::parse("csv").is_none());
+
+        let value = json!({"size": {"total": 10}, "layers": [{"nodes": 3}, {"nodes": 1}]});
+        assert_eq!(
+            flatten("", &value),
+            [
+                ("layers.0.nodes".to_string(), json!(3)),
+                ("layers.1.nodes".to_string(), json!(1)),
+                ("size.total".to_string(), json!(10)),
+            ]
+        );
+    }
+}
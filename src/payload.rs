@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use serde_json::json;
+
+/*
+Key/value data stored next to a node's vector, so the document a vector came
+from can live in the same file. Values are flat: strings, numbers, bools and
+lists of string tags.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Tags(Vec<String>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Payload {
+    pub fields: BTreeMap<String, Value>,
+}
+
+impl Payload {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    // a flat json object, arrays may only hold strings
+    pub fn from_json(value: &serde_json::Value) -> Result<Payload, String> {
+        let object = value.as_object().ok_or("payload must be a json object")?;
+        let mut fields = BTreeMap::new();
+        for (key, value) in object {
+            let value = match value {
+                serde_json::Value::String(s) => Value::String(s.clone()),
+                serde_json::Value::Number(n) => Value::Number(n.as_f64().unwrap()),
+                serde_json::Value::Bool(b) => Value::Bool(*b),
+                serde_json::Value::Array(tags) => Value::Tags(
+                    tags.iter()
+                        .map(|tag| tag.as_str().map(|t| t.to_string()))
+                        .collect::<Option<Vec<String>>>()
+                        .ok_or_else(|| format!("{}: tags must be strings", key))?,
+                ),
+                _ => return Err(format!("{}: must be a string, number, bool or tags", key)),
+            };
+            fields.insert(key.clone(), value);
+        }
+        Ok(Payload { fields })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let object = self
+            .fields
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(s) => json!(s),
+                    // whole numbers go back out the way they probably came in
+                    Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => json!(*n as i64),
+                    Value::Number(n) => json!(n),
+                    Value::Bool(b) => json!(b),
+                    Value::Tags(tags) => json!(tags),
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        serde_json::Value::Object(object)
+    }
+
+    /*
+    8       number of fields
+    per field:
+    8+n     key, length then utf8
+    1       kind (0 string, 1 number, 2 bool, 3 tags)
+            string: 8+n, number: 8, bool: 1, tags: 8 count then 8+n per tag
+    */
+    pub fn serialize(&self, collect: &mut Vec<u8>) {
+        collect.extend_from_slice(&(self.fields.len() as u64).to_be_bytes());
+        for (key, value) in &self.fields {
+            push_str(collect, key);
+            match value {
+                Value::String(s) => {
+                    collect.push(0);
+                    push_str(collect, s);
+                }
+                Value::Number(n) => {
+                    collect.push(1);
+                    collect.extend_from_slice(&n.to_be_bytes());
+                }
+                Value::Bool(b) => {
+                    collect.push(2);
+                    collect.push(*b as u8);
+                }
+                Value::Tags(tags) => {
+                    collect.push(3);
+                    collect.extend_from_slice(&(tags.len() as u64).to_be_bytes());
+                    tags.iter().for_each(|tag| push_str(collect, tag));
+                }
+            }
+        }
+    }
+
+    // reads one payload from the start of `bytes`, returns it and how many bytes it took
+    pub fn deserialize(bytes: &[u8]) -> io::Result<(Payload, usize)> {
+        let mut k = 0;
+        let mut fields = BTreeMap::new();
+        for _i in 0..read_u64(bytes, &mut k)? {
+            let key = read_str(bytes, &mut k)?;
+            let value = match read_bytes(bytes, &mut k, 1)?[0] {
+                0 => Value::String(read_str(bytes, &mut k)?),
+                1 => Value::Number(f64::from_bits(read_u64(bytes, &mut k)?)),
+                2 => Value::Bool(read_bytes(bytes, &mut k, 1)?[0] != 0),
+                3 => {
+                    let count = read_u64(bytes, &mut k)?;
+                    Value::Tags(
+                        (0..count)
+                            .map(|_| read_str(bytes, &mut k))
+                            .collect::<io::Result<_>>()?,
+                    )
+                }
+                kind => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown payload value kind {}", kind),
+                    ))
+                }
+            };
+            fields.insert(key, value);
+        }
+        Ok((Payload { fields }, k))
+    }
+
+    pub fn serialized_size(&self) -> usize {
+        let mut collect = Vec::new();
+        self.serialize(&mut collect);
+        collect.len()
+    }
+}
+
+fn push_str(collect: &mut Vec<u8>, s: &str) {
+    collect.extend_from_slice(&(s.len() as u64).to_be_bytes());
+    collect.extend_from_slice(s.as_bytes());
+}
+
+fn read_bytes<'a>(bytes: &'a [u8], k: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let read = k
+        .checked_add(len)
+        .and_then(|end| bytes.get(*k..end))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "payload is cut short"))?;
+    *k += len;
+    Ok(read)
+}
+
+fn read_u64(bytes: &[u8], k: &mut usize) -> io::Result<u64> {
+    Ok(u64::from_be_bytes(
+        read_bytes(bytes, k, 8)?.try_into().unwrap(),
+    ))
+}
+
+fn read_str(bytes: &[u8], k: &mut usize) -> io::Result<String> {
+    let len = read_u64(bytes, k)? as usize;
+    Ok(String::from_utf8_lossy(read_bytes(bytes, k, len)?).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_and_bytes_round_trip() {
+        let json =
+            json!({"title": "a", "year": 1965, "score": 0.5, "new": true, "tags": ["x", "y"]});
+        let payload = Payload::from_json(&json).unwrap();
+        assert_eq!(payload.to_json(), json);
+
+        let mut bytes = Vec::new();
+        payload.serialize(&mut bytes);
+        assert_eq!(bytes.len(), payload.serialized_size());
+        assert_eq!(
+            Payload::deserialize(&bytes).unwrap(),
+            (payload, bytes.len())
+        );
+        for end in 0..bytes.len() {
+            assert!(Payload::deserialize(&bytes[..end]).is_err());
+        }
+        // the kind byte of the first value, "new" sorts first
+        let kind = 8 + 8 + "new".len();
+        assert_eq!(bytes[kind], 2);
+        bytes[kind] = 4;
+        assert!(Payload::deserialize(&bytes).is_err());
+
+        assert!(Payload::from_json(&json!({"nested": {"a": 1}})).is_err());
+        assert!(Payload::from_json(&json!([1, 2])).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use numpy::ndarray::{ArrayView1, ArrayView2};
+use numpy::{AllowTypeChange, IntoPyArray, PyArray1, PyArrayLike1, PyArrayLike2};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::file::{write_collection, GraphFile, DEFAULT_COLLECTION};
+use crate::hnsw::{self, knn_search};
+use crate::metric::Metric;
+use crate::vector::ElementType;
+
+/*
+The `vlite` Python module, built with `maturin build --features python`:
+    g = vlite.Graph.new(first, m=4.0, m_max=16, m_max0=32, candidate_list_size=64, seed=None)
+    g.insert(vector)                 -> id
+    g.insert_batch(matrix)           -> ids, one row per vector
+    g.search(vector, k, ef=20)       -> (ids, distances)
+    g.save("index.vlite")
+    g = vlite.Graph.load("index.vlite")
+Vectors are numpy arrays or anything numpy can turn into float64, with finite
+components. Bad arguments raise ValueError before the graph is touched, a batch
+with one bad row inserts nothing. Files are the same .vlite files the command
+line reads and writes.
+*/
+// ids and distances, nearest first
+type SearchArrays<'py> = (Bound<'py, PyArray1<u64>>, Bound<'py, PyArray1<f64>>);
+
+#[pyclass(name = "Graph", unsendable)]
+pub struct PyGraph {
+    g: Box<hnsw::Graph>,
+}
+
+#[pymethods]
+impl PyGraph {
+    #[staticmethod]
+    #[pyo3(signature = (first, m=4.0, m_max=16, m_max0=32, candidate_list_size=64, metric="cosine", element_type="f64", seed=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        first: PyArrayLike1<'_, f64, AllowTypeChange>,
+        m: f64,
+        m_max: usize,
+        m_max0: usize,
+        candidate_list_size: usize,
+        metric: &str,
+        element_type: &str,
+        seed: Option<u64>,
+    ) -> PyResult<Self> {
+        let metric = Metric::parse(metric)
+            .ok_or_else(|| PyValueError::new_err("metric must be one of cosine, l2 or dot"))?;
+        let element_type = ElementType::parse(element_type).ok_or_else(|| {
+            PyValueError::new_err("element_type must be one of f64, f32, f16 or bf16")
+        })?;
+        let first = vector(first.as_array()).map_err(PyValueError::new_err)?;
+        if first.is_empty() {
+            return Err(PyValueError::new_err("the first vector is empty"));
+        }
+        for (name, value) in [
+            ("m_max", m_max),
+            ("m_max0", m_max0),
+            ("candidate_list_size", candidate_list_size),
+        ] {
+            if value == 0 {
+                return Err(PyValueError::new_err(format!(
+                    "{} must be at least 1",
+                    name
+                )));
+            }
+        }
+        let g = hnsw::Graph::new(
+            &first,
+            m,
+            m_max,
+            m_max0,
+            candidate_list_size,
+            metric,
+            element_type,
+            seed.unwrap_or_else(rand::random),
+        )
+        .map_err(PyValueError::new_err)?;
+        Ok(PyGraph { g: Box::new(g) })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (path, collection=DEFAULT_COLLECTION))]
+    fn load(path: &str, collection: &str) -> PyResult<Self> {
+        let g = GraphFile::open(path.to_string())
+            .and_then(|mut file| file.read_collection(collection))
+            .and_then(|mut g| {
+                g.try_weaken_ep()?;
+                g.try_link_friends()?;
+                Ok(g)
+            })
+            .map_err(|e| PyIOError::new_err(format!("{}: {}", path, e)))?;
+        Ok(PyGraph { g })
+    }
+
+    // replaces the collection in the file and keeps the others
+    #[pyo3(signature = (path, collection=DEFAULT_COLLECTION))]
+    fn save(&self, path: &str, collection: &str) -> PyResult<()> {
+        write_collection(path, collection, &self.g)
+            .map_err(|e| PyIOError::new_err(format!("{}: {}", path, e)))
+    }
+
+    fn insert(&mut self, vector: PyArrayLike1<'_, f64, AllowTypeChange>) -> PyResult<usize> {
+        let q = self.query(vector.as_array())?;
+        Ok(self.g.insert(&q))
+    }
+
+    fn insert_batch<'py>(
+        &mut self,
+        py: Python<'py>,
+        vectors: PyArrayLike2<'py, f64, AllowTypeChange>,
+    ) -> PyResult<Bound<'py, PyArray1<u64>>> {
+        let vectors = self.queries(vectors.as_array())?;
+        let ids: Vec<u64> = vectors.iter().map(|q| self.g.insert(q) as u64).collect();
+        Ok(ids.into_pyarray(py))
+    }
+
+    #[pyo3(signature = (vector, k, ef=20))]
+    fn search<'py>(
+        &self,
+        py: Python<'py>,
+        vector: PyArrayLike1<'py, f64, AllowTypeChange>,
+        k: usize,
+        ef: usize,
+    ) -> PyResult<SearchArrays<'py>> {
+        let q = self.query(vector.as_array())?;
+        let results = knn_search(&self.g, &q, k, ef);
+        let ids: Vec<u64> = results.iter().map(|r| r.id as u64).collect();
+        let distances: Vec<f64> = results.iter().map(|r| r.distance).collect();
+        Ok((ids.into_pyarray(py), distances.into_pyarray(py)))
+    }
+
+    #[getter]
+    fn dimension(&self) -> usize {
+        self.g.dimension
+    }
+
+    #[getter]
+    fn metric(&self) -> &'static str {
+        self.g.metric.name()
+    }
+
+    fn __len__(&self) -> usize {
+        self.g.nodes.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Graph(nodes={}, dimension={}, metric={})",
+            self.g.nodes.len(),
+            self.g.dimension,
+            self.g.metric.name()
+        )
+    }
+}
+
+impl PyGraph {
+    // a vector to insert or search for, the graph's length and finite
+    fn query(&self, array: ArrayView1<f64>) -> PyResult<Vec<f64>> {
+        self.check_dimension(array.len())?;
+        vector(array).map_err(PyValueError::new_err)
+    }
+
+    // every row is checked before any is inserted
+    fn queries(&self, matrix: ArrayView2<f64>) -> PyResult<Vec<Vec<f64>>> {
+        self.check_dimension(matrix.ncols())?;
+        matrix
+            .rows()
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                vector(row).map_err(|e| PyValueError::new_err(format!("row {}: {}", i, e)))
+            })
+            .collect()
+    }
+
+    fn check_dimension(&self, dimension: usize) -> PyResult<()> {
+        if dimension != self.g.dimension {
+            return Err(PyValueError::new_err(format!(
+                "expected vectors of length {}, got {}",
+                self.g.dimension, dimension
+            )));
+        }
+        Ok(())
+    }
+}
+
+// NaN or infinity would poison every distance measured against the vector
+fn vector(array: ArrayView1<f64>) -> Result<Vec<f64>, String> {
+    if !array.iter().all(|x| x.is_finite()) {
+        return Err("vector components must be finite".to_string());
+    }
+    Ok(array.to_vec())
+}
+
+#[pymodule]
+fn vlite(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyGraph>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use numpy::ndarray::{arr1, arr2};
+    use pyo3::types::PyDict;
+
+    use super::*;
+    use crate::file::write_collection;
+    use crate::hnsw::tests::random_graph;
+    use crate::testing::temp_path;
+
+    #[test]
+    fn vectors_are_checked() {
+        assert_eq!(vector(arr1(&[1.0, 2.0]).view()), Ok(vec![1.0, 2.0]));
+        assert!(vector(arr1(&[1.0, f64::NAN]).view()).is_err());
+        assert!(vector(arr1(&[f64::NEG_INFINITY, 2.0]).view()).is_err());
+
+        let g = PyGraph {
+            g: Box::new(random_graph(2, 10, Metric::Cosine, ElementType::F64, 0)),
+        };
+        assert!(g.query(arr1(&[1.0, 2.0]).view()).is_ok());
+        assert!(g.query(arr1(&[1.0, 2.0, 3.0]).view()).is_err());
+        let rows = g.queries(arr2(&[[1.0, 2.0], [3.0, 4.0]]).view()).unwrap();
+        assert_eq!(rows, [[1.0, 2.0], [3.0, 4.0]]);
+        assert!(g
+            .queries(arr2(&[[1.0, 2.0], [f64::NAN, 4.0]]).view())
+            .is_err());
+        assert!(g.queries(arr2(&[[1.0, 2.0, 3.0]]).view()).is_err());
+    }
+
+    /*
+    Drives the module from Python the way a user would. NumPy has to be
+    installed in the interpreter the tests link against for the half that
+    builds and searches graphs, without it only loading and saving are run.
+    */
+    #[test]
+    fn module_round_trip() {
+        let path = temp_path("python.vlite");
+        let path = path.to_str().unwrap();
+        let g = random_graph(3, 50, Metric::Euclidean, ElementType::F32, 0);
+        write_collection(path, DEFAULT_COLLECTION, &g).unwrap();
+
+        Python::initialize();
+        Python::attach(|py| {
+            let module = pyo3::wrap_pymodule!(vlite)(py);
+            let modules = py.import("sys").unwrap().getattr("modules").unwrap();
+            modules.set_item("vlite", module).unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("path", path).unwrap();
+            let numpy = py.import("numpy").is_ok();
+            locals.set_item("numpy", numpy).unwrap();
+            if !numpy {
+                eprintln!("numpy is not installed, only loading and saving are tested");
+            }
+            py.run(
+                cr#"
+import vlite
+
+g = vlite.Graph.load(path)
+assert (len(g), g.dimension, g.metric) == (50, 3, "l2"), repr(g)
+g.save(path, "copy")
+assert len(vlite.Graph.load(path, collection="copy")) == 50
+for missing in [(path + ".missing",), (path, "missing")]:
+    try:
+        vlite.Graph.load(*missing)
+        assert False, missing
+    except OSError:
+        pass
+
+if numpy:
+    import numpy as np
+
+    # ints and float32 are turned into float64, lists work too
+    g = vlite.Graph.new(np.array([1, 0, 0]), metric="l2", seed=7)
+    assert g.insert(np.array([0, 1, 0], dtype=np.float32)) == 1
+    ids = g.insert_batch([[0, 0, 1], [1, 1, 0], [0.5, 0.5, 0.5]])
+    assert ids.dtype == np.uint64 and list(ids) == [2, 3, 4]
+    ids, distances = g.search([0, 0, 1], 2)
+    assert ids.dtype == np.uint64 and distances.dtype == np.float64
+    assert ids[0] == 2 and distances[0] == 0.0 and distances[1] > 0.0
+
+    def refused(call, *args, **kwargs):
+        try:
+            call(*args, **kwargs)
+        except ValueError:
+            return True
+        return False
+
+    assert refused(vlite.Graph.new, [1.0, 0.0], m=1.0)
+    assert refused(vlite.Graph.new, [1.0, 0.0], m_max=0)
+    assert refused(vlite.Graph.new, [1.0, 0.0], metric="hamming")
+    assert refused(vlite.Graph.new, [])
+    assert refused(g.insert, [1.0, 0.0])
+    assert refused(g.insert, [float("nan"), 0.0, 0.0])
+    assert refused(g.insert_batch, [[0.0, 0.0, 1.0], [0.0, float("inf"), 0.0]])
+    assert refused(g.search, [1.0, 0.0], 1)
+    assert len(g) == 5
+
+    # a graph read back searches the same
+    g.save(path)
+    h = vlite.Graph.load(path)
+    query = np.random.default_rng(0).random(3)
+    assert all(np.array_equal(a, b) for a, b in zip(g.search(query, 5), h.search(query, 5)))
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,597 @@
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::io;
+
+use crate::metric::{cosine_from_parts, Metric};
+
+/*
+Compressed copies of the node vectors. Codes are used to walk the graph,
+the full precision vectors re-rank whatever the walk finds.
+
+Codes are kept next to the full vectors unless the graph drops its vectors
+(`Graph::drop_vectors`), then the codes are all that is left of each vector and
+distances stay approximate.
+*/
+pub enum Quantizer {
+    Scalar(ScalarQuantizer),
+    Product(ProductQuantizer),
+    Binary(BinaryQuantizer),
+}
+
+impl Quantizer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantizer::Scalar(_) => "int8",
+            Quantizer::Product(_) => "pq",
+            Quantizer::Binary(_) => "binary",
+        }
+    }
+
+    // whether searches re-rank what the codes found with the full vectors
+    pub fn rerank(&self) -> bool {
+        match self {
+            Quantizer::Binary(bq) => bq.rerank,
+            _ => true,
+        }
+    }
+
+    // an untrained quantizer encodes everything as empty codes
+    pub fn encode(&self, v: &[f64]) -> Box<[u8]> {
+        match self {
+            Quantizer::Scalar(sq) => sq.encode(v),
+            Quantizer::Product(pq) => pq.encode(v),
+            Quantizer::Binary(_) => BinaryQuantizer::encode(v),
+        }
+    }
+
+    // whether `codes` could have come from `encode`, empty codes always could
+    pub fn fits(&self, codes: &[u8], dimension: usize) -> bool {
+        codes.is_empty()
+            || match self {
+                Quantizer::Scalar(_) => codes.len() == dimension,
+                Quantizer::Product(pq) => {
+                    pq.is_trained()
+                        && codes.len() == pq.subspaces
+                        && codes.iter().all(|&c| (c as usize) < pq.centroids)
+                }
+                Quantizer::Binary(_) => codes.len() == dimension.div_ceil(8),
+            }
+    }
+
+    // the vector `codes` stand for, as close as the codes can get to the one encoded
+    pub fn decode(&self, codes: &[u8], dimension: usize) -> Vec<f64> {
+        match self {
+            Quantizer::Scalar(sq) => sq.decode(codes),
+            Quantizer::Product(pq) => pq.decode(codes),
+            Quantizer::Binary(_) => BinaryQuantizer::decode(codes, dimension),
+        }
+    }
+
+    // everything a search needs to measure a full precision query against codes
+    pub fn prepare<'a>(&'a self, metric: Metric, q: &[f64]) -> PreparedQuery<'a> {
+        match self {
+            Quantizer::Scalar(sq) => PreparedQuery::Scalar(sq, metric, q.into()),
+            Quantizer::Product(pq) => PreparedQuery::Product(pq.table(metric, q)),
+            Quantizer::Binary(_) => PreparedQuery::Binary(BinaryQuantizer::encode(q)),
+        }
+    }
+
+    pub fn serialize(&self, collect: &mut Vec<u8>) {
+        match self {
+            Quantizer::Scalar(sq) => {
+                collect.push(1);
+                sq.min
+                    .iter()
+                    .chain(sq.max.iter())
+                    .for_each(|x| collect.extend_from_slice(&x.to_be_bytes()));
+            }
+            Quantizer::Product(pq) => {
+                collect.push(2);
+                collect.extend_from_slice(&(pq.subspaces as u64).to_be_bytes());
+                collect.extend_from_slice(&(pq.centroids as u64).to_be_bytes());
+                collect.extend_from_slice(&(pq.codebooks.len() as u64).to_be_bytes());
+                pq.codebooks
+                    .iter()
+                    .for_each(|x| collect.extend_from_slice(&x.to_be_bytes()));
+            }
+            Quantizer::Binary(bq) => {
+                collect.push(3);
+                collect.push(bq.rerank as u8);
+            }
+        }
+    }
+
+    // an absent or zero kind byte means the graph isn't quantized, also gives how many bytes were read
+    pub fn deserialize(bytes: &[u8], dimension: usize) -> io::Result<(Option<Quantizer>, usize)> {
+        let short = || io::Error::new(io::ErrorKind::InvalidData, "quantizer is cut short");
+        match bytes.first() {
+            None => Ok((None, 0)),
+            Some(0) => Ok((None, 1)),
+            Some(1) => {
+                let count = dimension.checked_mul(2).ok_or_else(short)?;
+                let values = read_f64s(&bytes[1..], count).ok_or_else(short)?;
+                let quantizer = Quantizer::Scalar(ScalarQuantizer {
+                    min: values[..dimension].into(),
+                    max: values[dimension..].into(),
+                });
+                Ok((Some(quantizer), 1 + count * 8))
+            }
+            Some(2) => {
+                let params = read_u64s(&bytes[1..], 3).ok_or_else(short)?;
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid codebooks");
+                let pq =
+                    ProductQuantizer::new(dimension, params[0], params[1]).ok_or_else(invalid)?;
+                // untrained, or one centroid of every subspace for each centroid
+                if params[2] != 0 && Some(params[2]) != params[1].checked_mul(dimension) {
+                    return Err(invalid());
+                }
+                let codebooks = read_f64s(&bytes[25..], params[2]).ok_or_else(short)?;
+                let quantizer = Quantizer::Product(ProductQuantizer {
+                    codebooks: codebooks.into(),
+                    ..pq
+                });
+                Ok((Some(quantizer), 25 + params[2] * 8))
+            }
+            Some(3) => {
+                let rerank = *bytes.get(1).ok_or_else(short)?;
+                let quantizer = Quantizer::Binary(BinaryQuantizer {
+                    rerank: rerank != 0,
+                });
+                Ok((Some(quantizer), 2))
+            }
+            Some(b) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown quantizer {}", b),
+            )),
+        }
+    }
+}
+
+fn read_f64s(bytes: &[u8], count: usize) -> Option<Vec<f64>> {
+    Some(
+        bytes
+            .get(..count.checked_mul(8)?)?
+            .chunks(8)
+            .map(|x| f64::from_be_bytes(x.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+fn read_u64s(bytes: &[u8], count: usize) -> Option<Vec<usize>> {
+    Some(
+        bytes
+            .get(..count.checked_mul(8)?)?
+            .chunks(8)
+            .map(|x| u64::from_be_bytes(x.try_into().unwrap()) as usize)
+            .collect(),
+    )
+}
+
+/*
+A query measured against codes. Product queries are turned into a lookup table
+once, after that every distance is one table read per subspace.
+*/
+pub enum PreparedQuery<'a> {
+    Scalar(&'a ScalarQuantizer, Metric, Box<[f64]>),
+    Product(DistanceTable),
+    // sign bits of the query
+    Binary(Box<[u8]>),
+}
+
+impl<'a> PreparedQuery<'a> {
+    pub fn distance(&self, codes: &[u8]) -> f64 {
+        match self {
+            PreparedQuery::Scalar(sq, metric, q) => sq.distance(*metric, q, codes),
+            PreparedQuery::Product(table) => table.distance(codes),
+            PreparedQuery::Binary(bits) => hamming(bits, codes) as f64,
+        }
+    }
+}
+
+/*
+Maps every dimension onto 0..=255 between the smallest and largest value seen
+for that dimension while training. Values outside the range are clamped.
+*/
+pub struct ScalarQuantizer {
+    pub min: Box<[f64]>,
+    pub max: Box<[f64]>,
+}
+
+impl ScalarQuantizer {
+    pub fn train(vectors: &[Vec<f64>]) -> Self {
+        let dimension = vectors.first().map_or(0, |v| v.len());
+        let mut min = vec![f64::INFINITY; dimension];
+        let mut max = vec![f64::NEG_INFINITY; dimension];
+        for v in vectors {
+            for i in 0..dimension {
+                min[i] = min[i].min(v[i]);
+                max[i] = max[i].max(v[i]);
+            }
+        }
+        ScalarQuantizer {
+            min: min.into(),
+            max: max.into(),
+        }
+    }
+
+    fn step(&self, i: usize) -> f64 {
+        (self.max[i] - self.min[i]) / 255.0
+    }
+
+    pub fn encode(&self, v: &[f64]) -> Box<[u8]> {
+        (0..v.len())
+            .map(|i| {
+                let step = self.step(i);
+                if step > 0.0 {
+                    ((v[i] - self.min[i]) / step).round().clamp(0.0, 255.0) as u8
+                } else {
+                    0
+                }
+            })
+            .collect()
+    }
+
+    pub fn decode(&self, codes: &[u8]) -> Vec<f64> {
+        (0..codes.len())
+            .map(|i| self.min[i] + codes[i] as f64 * self.step(i))
+            .collect()
+    }
+
+    pub fn distance(&self, metric: Metric, q: &[f64], codes: &[u8]) -> f64 {
+        let mut dot = 0.0;
+        let mut q_sum = 0.0;
+        let mut x_sum = 0.0;
+        let mut l2 = 0.0;
+        for i in 0..q.len() {
+            let x = self.min[i] + codes[i] as f64 * self.step(i);
+            match metric {
+                Metric::Euclidean => l2 += (q[i] - x).powi(2),
+                Metric::Dot => dot += q[i] * x,
+                Metric::Cosine => {
+                    dot += q[i] * x;
+                    q_sum += q[i].powi(2);
+                    x_sum += x.powi(2);
+                }
+            }
+        }
+        match metric {
+            Metric::Euclidean => l2.sqrt(),
+            Metric::Dot => -dot,
+            Metric::Cosine => cosine_from_parts(dot, q_sum.sqrt(), x_sum.sqrt()),
+        }
+    }
+}
+
+/*
+Splits vectors into `subspaces` equal chunks and replaces every chunk with the
+nearest of `centroids` k-means centroids trained for that chunk, so a vector is
+stored as one byte per subspace.
+*/
+pub struct ProductQuantizer {
+    pub subspaces: usize,
+    pub centroids: usize,
+    pub dimension: usize,
+    // subspaces * centroids * (dimension / subspaces) values, empty until trained
+    pub codebooks: Box<[f64]>,
+}
+
+// rounds of k-means per subspace
+const KMEANS_ITERATIONS: usize = 25;
+// codebooks are trained on a sample of at most this many vectors per centroid
+const TRAIN_PER_CENTROID: usize = 64;
+
+impl ProductQuantizer {
+    // `dimension` has to split evenly into `subspaces`, at most 256 centroids fit a byte
+    pub fn new(dimension: usize, subspaces: usize, centroids: usize) -> Option<Self> {
+        if subspaces == 0
+            || !dimension.is_multiple_of(subspaces)
+            || centroids == 0
+            || centroids > 256
+        {
+            return None;
+        }
+        Some(ProductQuantizer {
+            subspaces,
+            centroids,
+            dimension,
+            codebooks: Box::new([]),
+        })
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.codebooks.is_empty()
+    }
+
+    fn sub_dimension(&self) -> usize {
+        self.dimension / self.subspaces
+    }
+
+    fn centroid(&self, subspace: usize, centroid: usize) -> &[f64] {
+        let d = self.sub_dimension();
+        let start = (subspace * self.centroids + centroid) * d;
+        &self.codebooks[start..start + d]
+    }
+
+    /*
+    Whether a graph growing to `count` vectors should (re)train the codebooks.
+    They are trained once there are `centroids` vectors and again every time the
+    count doubles, until the training sample is as large as it gets.
+    */
+    pub fn needs_training(&self, count: usize) -> bool {
+        if !self.is_trained() {
+            return count >= self.centroids;
+        }
+        count >= self.centroids
+            && count.is_multiple_of(self.centroids)
+            && (count / self.centroids).is_power_of_two()
+            && count / 2 < self.centroids * TRAIN_PER_CENTROID
+    }
+
+    // needs at least `centroids` vectors, with fewer the quantizer stays untrained
+    pub fn train(&self, vectors: &[Vec<f64>], seed: u64) -> Self {
+        let mut codebooks = Vec::new();
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        if vectors.len() >= self.centroids {
+            let d = self.sub_dimension();
+            let limit = self.centroids * TRAIN_PER_CENTROID;
+            let vectors: Vec<&Vec<f64>> = if vectors.len() > limit {
+                sample(&mut rand::thread_rng(), vectors.len(), limit)
+                    .iter()
+                    .map(|i| &vectors[i])
+                    .collect()
+            } else {
+                vectors.iter().collect()
+            };
+            for s in 0..self.subspaces {
+                let points: Vec<&[f64]> = vectors.iter().map(|v| &v[s * d..(s + 1) * d]).collect();
+                kmeans(&mut rng, &points, self.centroids)
+                    .iter()
+                    .for_each(|c| codebooks.extend_from_slice(c));
+            }
+        }
+        ProductQuantizer {
+            subspaces: self.subspaces,
+            centroids: self.centroids,
+            dimension: self.dimension,
+            codebooks: codebooks.into(),
+        }
+    }
+
+    pub fn encode(&self, v: &[f64]) -> Box<[u8]> {
+        if !self.is_trained() {
+            return Box::new([]);
+        }
+        let d = self.sub_dimension();
+        (0..self.subspaces)
+            .map(|s| {
+                let sub = &v[s * d..(s + 1) * d];
+                (0..self.centroids)
+                    .map(|c| (c, squared_distance(sub, self.centroid(s, c))))
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap()
+                    .0 as u8
+            })
+            .collect()
+    }
+
+    // the centroid of every subspace, one after the other
+    pub fn decode(&self, codes: &[u8]) -> Vec<f64> {
+        codes
+            .iter()
+            .enumerate()
+            .flat_map(|(s, &c)| self.centroid(s, c as usize))
+            .copied()
+            .collect()
+    }
+
+    /*
+    Partial results of the metric between every query chunk and every centroid of
+    that chunk. Cosine also needs the squared norm of each centroid, those go in
+    a second half of the table.
+    */
+    pub fn table(&self, metric: Metric, q: &[f64]) -> DistanceTable {
+        let d = self.sub_dimension();
+        let size = self.subspaces * self.centroids;
+        let mut table = vec![
+            0.0;
+            if metric == Metric::Cosine {
+                size * 2
+            } else {
+                size
+            }
+        ];
+        for s in 0..self.subspaces {
+            let sub = &q[s * d..(s + 1) * d];
+            for c in 0..self.centroids {
+                let centroid = self.centroid(s, c);
+                let i = s * self.centroids + c;
+                match metric {
+                    Metric::Euclidean => table[i] = squared_distance(sub, centroid),
+                    Metric::Dot => table[i] = crate::metric::dot(sub, centroid),
+                    Metric::Cosine => {
+                        table[i] = crate::metric::dot(sub, centroid);
+                        table[size + i] = crate::metric::dot(centroid, centroid);
+                    }
+                }
+            }
+        }
+        DistanceTable {
+            metric,
+            centroids: self.centroids,
+            q_norm: crate::metric::dot(q, q).sqrt(),
+            table: table.into(),
+        }
+    }
+}
+
+pub struct DistanceTable {
+    metric: Metric,
+    centroids: usize,
+    q_norm: f64,
+    table: Box<[f64]>,
+}
+
+impl DistanceTable {
+    pub fn distance(&self, codes: &[u8]) -> f64 {
+        let size = codes.len() * self.centroids;
+        let mut sum = 0.0;
+        let mut norm = 0.0;
+        for (s, &code) in codes.iter().enumerate() {
+            let i = s * self.centroids + code as usize;
+            sum += self.table[i];
+            if self.metric == Metric::Cosine {
+                norm += self.table[size + i];
+            }
+        }
+        match self.metric {
+            Metric::Euclidean => sum.sqrt(),
+            Metric::Dot => -sum,
+            Metric::Cosine => cosine_from_parts(sum, self.q_norm, norm.sqrt()),
+        }
+    }
+}
+
+fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+// lloyd's algorithm seeded with `k` distinct points, empty clusters keep their old centroid
+fn kmeans(rng: &mut ChaCha8Rng, points: &[&[f64]], k: usize) -> Vec<Vec<f64>> {
+    let d = points[0].len();
+    let mut centroids: Vec<Vec<f64>> = sample(rng, points.len(), k)
+        .iter()
+        .map(|i| points[i].to_vec())
+        .collect();
+
+    let mut assignment = vec![usize::MAX; points.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (p, point) in points.iter().enumerate() {
+            let nearest = (0..k)
+                .map(|c| (c, squared_distance(point, &centroids[c])))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap()
+                .0;
+            if nearest != assignment[p] {
+                assignment[p] = nearest;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![vec![0.0; d]; k];
+        let mut counts = vec![0; k];
+        for (p, point) in points.iter().enumerate() {
+            counts[assignment[p]] += 1;
+            sums[assignment[p]]
+                .iter_mut()
+                .zip(point.iter())
+                .for_each(|(s, x)| *s += x);
+        }
+        for c in 0..k {
+            if counts[c] > 0 {
+                centroids[c] = sums[c].iter().map(|s| s / counts[c] as f64).collect();
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+    centroids
+}
+
+/*
+Keeps one sign bit per dimension, eight dimensions to a byte, and measures the
+Hamming distance between bits. Works best for embeddings centered around zero.
+Without re-ranking, search distances are bit counts instead of the graph metric.
+*/
+pub struct BinaryQuantizer {
+    pub rerank: bool,
+}
+
+impl BinaryQuantizer {
+    // bit i of byte i / 8 is set when dimension i is positive
+    pub fn encode(v: &[f64]) -> Box<[u8]> {
+        v.chunks(8)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &x)| x > 0.0)
+                    .fold(0u8, |bits, (i, _)| bits | 1 << i)
+            })
+            .collect()
+    }
+
+    // 1 for every set bit and -1 for every clear one
+    pub fn decode(codes: &[u8], dimension: usize) -> Vec<f64> {
+        (0..dimension)
+            .map(|i| {
+                if codes[i / 8] & 1 << (i % 8) != 0 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            })
+            .collect()
+    }
+}
+
+// number of differing bits, a u64 at a time
+fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    let mut a8 = a.chunks_exact(8);
+    let mut b8 = b.chunks_exact(8);
+    let mut sum = 0;
+    for (x, y) in (&mut a8).zip(&mut b8) {
+        let x = u64::from_ne_bytes(x.try_into().unwrap());
+        let y = u64::from_ne_bytes(y.try_into().unwrap());
+        sum += (x ^ y).count_ones();
+    }
+    sum + a8
+        .remainder()
+        .iter()
+        .zip(b8.remainder())
+        .map(|(x, y)| (x ^ y).count_ones())
+        .sum::<u32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn product_quantizers_retrain_as_graphs_double() {
+        let pq = ProductQuantizer::new(8, 4, 16).unwrap();
+        assert!(!pq.needs_training(15));
+        assert!(pq.needs_training(16));
+        assert!(pq.needs_training(40));
+
+        let vectors: Vec<Vec<f64>> = (0..16).map(|i| vec![i as f64; 8]).collect();
+        let pq = pq.train(&vectors, 0);
+        let retrained: Vec<usize> = (1..10_000).filter(|&n| pq.needs_training(n)).collect();
+        assert_eq!(retrained, [16, 32, 64, 128, 256, 512, 1024]);
+    }
+
+    #[test]
+    fn codebooks_must_match_the_parameters() {
+        let vectors: Vec<Vec<f64>> = (0..16).map(|i| vec![i as f64; 8]).collect();
+        let pq = ProductQuantizer::new(8, 4, 16).unwrap().train(&vectors, 0);
+        let mut bytes = Vec::new();
+        Quantizer::Product(pq).serialize(&mut bytes);
+        assert!(Quantizer::deserialize(&bytes, 8).is_ok());
+        // 4 subspaces don't split 6 dimensions
+        assert!(Quantizer::deserialize(&bytes, 6).is_err());
+
+        // one centroid value short
+        let mut short = bytes[..bytes.len() - 8].to_vec();
+        short[17..25].copy_from_slice(&(16 * 8 - 1u64).to_be_bytes());
+        let error = Quantizer::deserialize(&short, 8).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // more centroids than fit a byte
+        let mut wide = bytes.clone();
+        wide[9..17].copy_from_slice(&300u64.to_be_bytes());
+        assert!(Quantizer::deserialize(&wide, 8).is_err());
+    }
+}
@@ -0,0 +1,622 @@
+use log::{error, info};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::{graph_path, insert_node, resolve_node, search_graph, NodeOptions, SearchOptions};
+use vite::condition::Condition;
+use vite::file::{table_size, write_collection, GraphFile};
+use vite::hnsw::{Budget, Graph};
+use vite::output::{node_json, search_rows, Format};
+use vite::payload::Payload;
+use vite::stats::GraphStats;
+
+/*
+A small HTTP/1.1 server over one collection, one request per connection:
+    POST   /search        {"vector": [..], "k": 10, "where": "..", "ids": [..],
+                           "exact": false, "vectors": false, "similarity": false,
+                           "max_distances": n, "max_visited": n, "timeout_ms": n}
+    POST   /vectors       {"vector": [..], "key": "..", "payload": {..}, "upsert": false}
+    GET    /vectors/{key or id}
+    DELETE /vectors/{key or id}
+    GET    /stats
+Bodies and replies are json, errors are {"error": ".."}. Every connection is read
+and answered on its own thread, the graph isn't Send so it stays on the thread
+that called `serve` and runs the requests one at a time. Every write rewrites the
+file, so readers of the file always see what the server has acknowledged. A
+write that can't be saved answers 500 and is taken back, see `save_inserts`.
+*/
+pub struct Server {
+    filename: String,
+    collection: String,
+    pub g: Box<Graph>,
+}
+
+// a reply body, or a status code and an error message
+type Reply = Result<(u16, Value), (u16, String)>;
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+// larger request lines and headers, or bodies, are turned away before they are read
+const MAX_HEAD: u64 = 16 * 1024;
+const MAX_BODY: usize = 16 * 1024 * 1024;
+// how long a client has to send its whole request, and then to read the reply
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Server {
+    pub fn new(filename: &str, collection: &str, g: Box<Graph>) -> Self {
+        Server {
+            filename: filename.to_string(),
+            collection: collection.to_string(),
+            g,
+        }
+    }
+
+    // runs until the listener fails, a slow client only holds up its own connection
+    pub fn serve(&mut self, listener: TcpListener) {
+        let (requests, queue) = mpsc::channel::<(Request, mpsc::Sender<Reply>)>();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let requests = requests.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle(stream, requests) {
+                                error!("connection failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("accept failed: {}", e),
+                }
+            }
+        });
+        for (request, reply) in queue {
+            let answer = self.route(&request);
+            let status = answer.as_ref().map_or_else(|e| e.0, |r| r.0);
+            info!("{} {} {}", request.method, request.path, status);
+            let _ = reply.send(answer);
+        }
+    }
+
+    fn route(&mut self, request: &Request) -> Reply {
+        let body = || -> Result<Value, (u16, String)> {
+            serde_json::from_slice(&request.body).map_err(|e| (400, format!("body: {}", e)))
+        };
+        let path = request.path.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["search"]) => self.search(&body()?),
+            ("POST", ["vectors"]) => self.add(&body()?),
+            ("GET", ["vectors", key_or_id]) => {
+                let id = self.resolve(key_or_id)?;
+                Ok((200, node_json(&self.g, id)))
+            }
+            ("DELETE", ["vectors", key_or_id]) => {
+                let id = self.resolve(key_or_id)?;
+                if self.g.delete(id) {
+                    self.save().inspect_err(|_| self.undelete(id))?;
+                }
+                Ok((200, json!({ "id": id, "deleted": true })))
+            }
+            ("GET", ["stats"]) => Ok((200, self.stats())),
+            (_, ["search"] | ["vectors"] | ["vectors", _] | ["stats"]) => {
+                Err((405, format!("{} not allowed on {}", request.method, path)))
+            }
+            _ => Err((404, format!("no route {}", path))),
+        }
+    }
+
+    fn search(&self, body: &Value) -> Reply {
+        let q = self.vector(body)?;
+        let k = match body.get("k") {
+            Some(k) => k.as_u64().ok_or((400, "k must be a number".to_string()))? as usize,
+            None => 10,
+        };
+        let ids = match body.get("ids") {
+            Some(ids) => Some(
+                ids.as_array()
+                    .and_then(|ids| ids.iter().map(|id| id.as_u64()).collect::<Option<Vec<_>>>())
+                    .ok_or((400, "ids must be a list of numbers".to_string()))?
+                    .into_iter()
+                    .map(|id| id as usize)
+                    .collect(),
+            ),
+            None => None,
+        };
+        let condition = match body.get("where") {
+            Some(condition) => Some(
+                condition
+                    .as_str()
+                    .ok_or("where must be a string".to_string())
+                    .and_then(Condition::parse)
+                    .map_err(|e| (400, e))?,
+            ),
+            None => None,
+        };
+        let flag = |name: &str| body.get(name).and_then(|v| v.as_bool()).unwrap_or(false);
+        let limit = |name: &str| match body.get(name) {
+            Some(limit) => limit
+                .as_u64()
+                .map(Some)
+                .ok_or((400, format!("{} must be a number", name))),
+            None => Ok(None),
+        };
+        let budget = Budget {
+            distances: limit("max_distances")?.map(|n| n as usize),
+            visited: limit("max_visited")?.map(|n| n as usize),
+            time: limit("timeout_ms")?.map(Duration::from_millis),
+        };
+        let options = SearchOptions {
+            format: Format::Json,
+            vectors: flag("vectors"),
+            similarity: flag("similarity"),
+            exact: flag("exact"),
+            ids,
+            condition,
+            budget,
+        };
+        let search = search_graph(&self.g, &q, k, &options);
+        let rows = search_rows(
+            &self.g,
+            &search.results,
+            options.vectors,
+            options.similarity,
+        );
+        Ok((200, json!({ "results": rows, "partial": search.partial })))
+    }
+
+    fn add(&mut self, body: &Value) -> Reply {
+        let q = self.vector(body)?;
+        let key = match body.get("key") {
+            Some(key) => Some(
+                key.as_str()
+                    .ok_or((400, "key must be a string".to_string()))?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let payload = match body.get("payload") {
+            Some(payload) => Payload::from_json(payload).map_err(|e| (400, e))?,
+            None => Payload::default(),
+        };
+        let node = NodeOptions {
+            payload,
+            key: key.clone(),
+            upsert: body
+                .get("upsert")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+        };
+
+        let replaced = key.as_ref().and_then(|key| self.g.id_of(key));
+        match insert_node(&mut self.g, &q, node) {
+            Ok(id) => {
+                self.save_inserts(&[(id, replaced)])?;
+                Ok((201, json!({ "id": id })))
+            }
+            Err(existing) => Err((
+                409,
+                format!(
+                    "key {} is already node {}, set upsert to replace it",
+                    key.unwrap_or_default(),
+                    existing
+                ),
+            )),
+        }
+    }
+
+    fn stats(&self) -> Value {
+        self.graph_stats().to_json()
+    }
+
+    pub fn graph_stats(&self) -> GraphStats {
+        let path = graph_path(&self.filename);
+        let names = GraphFile::open(path.clone())
+            .and_then(|mut file| file.table())
+            .map(|table| table.into_iter().map(|entry| entry.name).collect())
+            .unwrap_or_else(|_| vec![self.collection.clone()]);
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let file_bytes = std::fs::metadata(&path).ok().map(|m| m.len());
+        GraphStats::new(&self.g, table_size(&names), file_bytes)
+    }
+
+    fn vector(&self, body: &Value) -> Result<Vec<f64>, (u16, String)> {
+        let q = body
+            .get("vector")
+            .and_then(|v| v.as_array())
+            .and_then(|v| v.iter().map(|x| x.as_f64()).collect::<Option<Vec<f64>>>())
+            .ok_or((400, "vector must be a list of numbers".to_string()))?;
+        if q.len() != self.g.dimension {
+            return Err((
+                400,
+                format!("expected a vector of length {}", self.g.dimension),
+            ));
+        }
+        Ok(q)
+    }
+
+    fn resolve(&self, key_or_id: &str) -> Result<usize, (u16, String)> {
+        let key_or_id = percent_decode(key_or_id);
+        resolve_node(&self.g, &key_or_id).ok_or((404, format!("no node {}", key_or_id)))
+    }
+
+    pub fn save(&self) -> Result<(), (u16, String)> {
+        let path = graph_path(&self.filename);
+        write_collection(&path, &self.collection, &self.g)
+            .map_err(|e| (500, format!("could not write {}: {}", path, e)))
+    }
+
+    /*
+    Saves the graph after `inserted`, each the id of a new node and the node its
+    key was upserted away from. If the file can't be written the inserts are
+    taken back, newest first, so what is in memory is what the file holds and a
+    retry starts from where the failed write did. A node can't be unlinked from
+    the graph, so it stays behind deleted, and replaced nodes get their keys back.
+    */
+    pub fn save_inserts(
+        &mut self,
+        inserted: &[(usize, Option<usize>)],
+    ) -> Result<(), (u16, String)> {
+        self.save().inspect_err(|_| {
+            for &(id, replaced) in inserted.iter().rev() {
+                self.g.delete(id);
+                if let Some(replaced) = replaced {
+                    self.undelete(replaced);
+                }
+            }
+        })
+    }
+
+    // takes back a delete that couldn't be saved
+    pub fn undelete(&mut self, id: usize) {
+        let mut node = self.g.nodes[id].borrow_mut();
+        node.deleted = false;
+        if let Some(key) = &node.key {
+            self.g.keys.insert(key.clone(), id);
+        }
+    }
+}
+
+// reads the request, has the graph thread answer it and writes the reply
+fn handle(
+    stream: TcpStream,
+    requests: mpsc::Sender<(Request, mpsc::Sender<Reply>)>,
+) -> io::Result<()> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut reader = Deadline {
+        stream: &stream,
+        deadline,
+    };
+    let request = read_request(&mut reader);
+    let unread = request.is_err();
+    let reply = request.and_then(|request| {
+        let (reply, answer) = mpsc::channel();
+        requests
+            .send((request, reply))
+            .map_err(|_| (500, "the server stopped".to_string()))?;
+        answer
+            .recv()
+            .map_err(|_| (500, "the server stopped".to_string()))?
+    });
+    let (status, body) = match reply {
+        Ok(reply) => reply,
+        Err((status, message)) => (status, json!({ "error": message })),
+    };
+
+    let body = body.to_string();
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        &stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    )?;
+    (&stream).flush()?;
+
+    // closing with the rest of a rejected request unread resets the connection,
+    // and the client can lose the reply, so take in a little more first
+    if unread {
+        stream.shutdown(Shutdown::Write)?;
+        reader.deadline = Instant::now() + TIMEOUT;
+        let _ = io::copy(&mut reader.take(MAX_HEAD), &mut io::sink());
+    }
+    Ok(())
+}
+
+// reads from a stream until `deadline`, however slowly the bytes trickle in
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the request took too long",
+            ));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+// the error is the status to answer with and why
+fn read_request(stream: &mut impl Read) -> Result<Request, (u16, String)> {
+    let bad = |e: io::Error| match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => (408, e.to_string()),
+        _ => (400, e.to_string()),
+    };
+    let mut reader = BufReader::new(stream);
+    let mut head = (&mut reader).take(MAX_HEAD);
+    let mut line = String::new();
+    head.read_line(&mut line).map_err(bad)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err((400, "bad request line".to_string())),
+    };
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if head.read_line(&mut line).map_err(bad)? == 0 {
+            if head.limit() == 0 {
+                return Err((431, "headers are too large".to_string()));
+            }
+            break;
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| (400, "bad content length".to_string()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err((413, format!("bodies are limited to {} bytes", MAX_BODY)));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(bad)?;
+    Ok(Request { method, path, body })
+}
+
+// keys in paths may be escaped, "%20" is a space
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_graph;
+    use crate::testing::{graph_file, reopen, small_graph, temp_path};
+    use vite::file::DEFAULT_COLLECTION;
+
+    fn graph() -> Box<Graph> {
+        Box::new(small_graph())
+    }
+
+    fn serve<F: FnOnce() -> Server + Send + 'static>(server: F) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || server().serve(listener));
+        address
+    }
+
+    fn send(address: std::net::SocketAddr, raw: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    fn request(
+        address: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> (u16, Value) {
+        send(
+            address,
+            &format!(
+                "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                path,
+                body.len(),
+                body
+            ),
+        )
+    }
+
+    #[test]
+    fn routes() {
+        let path = graph_file("server.vlite", &graph());
+        let filename = path.clone();
+        let address = serve(move || {
+            let g = read_graph(&filename, DEFAULT_COLLECTION);
+            Server::new(&filename, DEFAULT_COLLECTION, g)
+        });
+        let request = |method: &str, path: &str, body: &str| request(address, method, path, body);
+
+        let (status, body) = request(
+            "POST",
+            "/vectors",
+            r#"{"vector": [0, 1], "key": "a b", "payload": {"n": 1}}"#,
+        );
+        assert_eq!((status, body["id"].as_u64()), (201, Some(1)));
+        assert_eq!(
+            request("POST", "/vectors", r#"{"vector": [0, 1], "key": "a b"}"#).0,
+            409
+        );
+        assert_eq!(request("POST", "/vectors", r#"{"vector": [0]}"#).0, 400);
+
+        let (status, body) = request("POST", "/search", r#"{"vector": [0.1, 1], "k": 1}"#);
+        assert_eq!(status, 200);
+        assert_eq!(body["results"][0]["key"], "a b");
+        assert_eq!(body["partial"], false);
+        let (_, body) = request(
+            "POST",
+            "/search",
+            r#"{"vector": [0.1, 1], "where": "n > 1"}"#,
+        );
+        assert_eq!(body["results"].as_array().unwrap().len(), 0);
+        // ids past the last node match nothing instead of sizing a set after them
+        let (status, body) = request(
+            "POST",
+            "/search",
+            r#"{"vector": [0.1, 1], "ids": [18446744073709551615, 1000000000000, 0]}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["results"].as_array().unwrap().len(), 1);
+        let (_, body) = request(
+            "POST",
+            "/search",
+            r#"{"vector": [0.1, 1], "max_visited": 100}"#,
+        );
+        assert_eq!(body["partial"], false);
+        assert_eq!(body["results"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            request("POST", "/search", r#"{"vector": [0.1, 1], "k": -1}"#).0,
+            400
+        );
+        assert_eq!(
+            request(
+                "POST",
+                "/search",
+                r#"{"vector": [0.1, 1], "timeout_ms": -1}"#
+            )
+            .0,
+            400
+        );
+
+        assert_eq!(request("GET", "/vectors/a%20b", "").1["payload"]["n"], 1);
+        assert_eq!(request("DELETE", "/vectors/0", "").0, 200);
+        assert_eq!(request("GET", "/vectors/0", "").0, 404);
+        assert_eq!(request("GET", "/stats", "").1["deleted"], 1);
+        assert_eq!(request("PUT", "/stats", "").0, 405);
+        assert_eq!(request("GET", "/nothing", "").0, 404);
+
+        let g = reopen(&path);
+        assert_eq!(g.id_of("a b"), Some(1));
+        assert!(g.nodes[0].borrow().deleted);
+    }
+
+    #[test]
+    fn oversized_requests_are_turned_away() {
+        let address = serve(|| Server::new("unused", DEFAULT_COLLECTION, graph()));
+        let (status, _) = send(
+            address,
+            &format!(
+                "POST /search HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY + 1
+            ),
+        );
+        assert_eq!(status, 413);
+
+        let header = "x".repeat(MAX_HEAD as usize);
+        let (status, _) = send(
+            address,
+            &format!("GET /stats HTTP/1.1\r\nX: {}\r\n\r\n", header),
+        );
+        assert_eq!(status, 431);
+    }
+
+    #[test]
+    fn stalled_clients_hold_up_only_themselves() {
+        let address = serve(|| Server::new("unused", DEFAULT_COLLECTION, graph()));
+        let mut stalled = TcpStream::connect(address).unwrap();
+        stalled
+            .write_all(b"POST /search HTTP/1.1\r\nContent-Length: 100\r\n\r\n{")
+            .unwrap();
+        let started = Instant::now();
+        assert_eq!(request(address, "GET", "/stats", "").0, 200);
+        assert!(started.elapsed() < TIMEOUT / 2);
+        drop(stalled);
+    }
+
+    #[test]
+    fn failed_writes_are_server_errors() {
+        let missing = temp_path("missing_dir").join("graph.vlite");
+        let filename = missing.to_str().unwrap().to_string();
+        let address = serve(move || {
+            let mut g = graph();
+            g.insert_with_key(&[0.0, 1.0], "a", false).unwrap();
+            Server::new(&filename, DEFAULT_COLLECTION, g)
+        });
+        let (status, body) = request(address, "POST", "/vectors", r#"{"vector": [0, 1]}"#);
+        assert_eq!(status, 500);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("could not write"));
+
+        // what couldn't be saved is taken back, the node stays behind deleted
+        assert_eq!(request(address, "GET", "/vectors/2", "").0, 404);
+        let (_, body) = request(address, "POST", "/search", r#"{"vector": [0, 1]}"#);
+        assert_eq!(body["results"].as_array().unwrap().len(), 2);
+        let upsert = r#"{"vector": [1, 1], "key": "a", "upsert": true}"#;
+        assert_eq!(request(address, "POST", "/vectors", upsert).0, 500);
+        assert_eq!(request(address, "GET", "/vectors/a", "").1["id"], 1);
+        assert_eq!(request(address, "DELETE", "/vectors/a", "").0, 500);
+        assert_eq!(request(address, "GET", "/vectors/a", "").1["id"], 1);
+        assert_eq!(request(address, "GET", "/stats", "").1["deleted"], 2);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::vector::Element;
+
+/*
+Distance kernels for f64 and f32 slices. x86_64 picks AVX2 + FMA at runtime and
+falls back to SSE2, which every x86_64 cpu has. aarch64 always has NEON. Anything
+else, and the f16/bf16 element types, use the portable loops.
+
+Sums are accumulated in f64 whatever the element type, like everywhere else.
+Both slices must have the same length, callers check vectors against the graph
+dimension before they get here. Release builds cut a longer slice to the shorter
+one rather than read past either.
+
+Short slices spend more time getting in and out of a kernel than in it, so below
+`min_len` the portable loop runs instead. The lengths are where the kernels
+started winning in `cargo bench --bench distances`.
+*/
+
+macro_rules! dispatch {
+    ($kernel:ident, $fallback:ident, $min_len:expr, $a:expr, $b:expr) => {{
+        debug_assert_eq!($a.len(), $b.len());
+        if $a.len() < $min_len {
+            return portable::$fallback($a, $b);
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+                return unsafe { avx2::$kernel($a, $b) };
+            }
+            return unsafe { sse2::$kernel($a, $b) };
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            return unsafe { neon::$kernel($a, $b) };
+        }
+        #[allow(unreachable_code)]
+        portable::$fallback($a, $b)
+    }};
+}
+
+pub fn dot_f64(a: &[f64], b: &[f64]) -> f64 {
+    dispatch!(dot_f64, dot, 16, a, b)
+}
+
+pub fn dot_f32(a: &[f32], b: &[f32]) -> f64 {
+    dispatch!(dot_f32, dot, 8, a, b)
+}
+
+pub fn l2_squared_f64(a: &[f64], b: &[f64]) -> f64 {
+    dispatch!(l2_squared_f64, l2_squared, 16, a, b)
+}
+
+pub fn l2_squared_f32(a: &[f32], b: &[f32]) -> f64 {
+    dispatch!(l2_squared_f32, l2_squared, 8, a, b)
+}
+
+pub fn cosine_parts_f64(a: &[f64], b: &[f64]) -> (f64, f64, f64) {
+    dispatch!(cosine_parts_f64, cosine_parts, 24, a, b)
+}
+
+pub fn cosine_parts_f32(a: &[f32], b: &[f32]) -> (f64, f64, f64) {
+    dispatch!(cosine_parts_f32, cosine_parts, 8, a, b)
+}
+
+// plain loops, generic so the half precision types can use them too
+pub mod portable {
+    use super::Element;
+
+    pub fn dot<T: Element>(a: &[T], b: &[T]) -> f64 {
+        debug_assert_eq!(a.len(), b.len());
+        a.iter().zip(b).map(|(x, y)| x.to_f64() * y.to_f64()).sum()
+    }
+
+    pub fn l2_squared<T: Element>(a: &[T], b: &[T]) -> f64 {
+        debug_assert_eq!(a.len(), b.len());
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| (x.to_f64() - y.to_f64()).powi(2))
+            .sum()
+    }
+
+    // a . b, a . a and b . b in one pass
+    pub fn cosine_parts<T: Element>(a: &[T], b: &[T]) -> (f64, f64, f64) {
+        debug_assert_eq!(a.len(), b.len());
+        let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+        for (x, y) in a.iter().zip(b) {
+            let (x, y) = (x.to_f64(), y.to_f64());
+            ab += x * y;
+            aa += x * x;
+            bb += y * y;
+        }
+        (ab, aa, bb)
+    }
+}
+
+/*
+Every arch module provides `$load` functions that read `LANES` elements from a
+pointer into an f64 register, plus `zero`, `fma`, `sub` and `sum`. The kernels
+are then the same for all of them: the body runs a register at a time and the
+tail that doesn't fill a register is finished with scalar code.
+*/
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+macro_rules! kernels {
+    ($feature:literal, $t:ty, $load:ident, $dot:ident, $l2:ident, $cos:ident) => {
+        #[target_feature(enable = $feature)]
+        pub unsafe fn $dot(a: &[$t], b: &[$t]) -> f64 {
+            let n = a.len().min(b.len());
+            let body = n - n % LANES;
+            let mut acc = zero();
+            let mut i = 0;
+            while i < body {
+                acc = fma($load(a.as_ptr().add(i)), $load(b.as_ptr().add(i)), acc);
+                i += LANES;
+            }
+            let mut total = sum(acc);
+            for j in body..n {
+                total += a[j] as f64 * b[j] as f64;
+            }
+            total
+        }
+
+        #[target_feature(enable = $feature)]
+        pub unsafe fn $l2(a: &[$t], b: &[$t]) -> f64 {
+            let n = a.len().min(b.len());
+            let body = n - n % LANES;
+            let mut acc = zero();
+            let mut i = 0;
+            while i < body {
+                let d = sub($load(a.as_ptr().add(i)), $load(b.as_ptr().add(i)));
+                acc = fma(d, d, acc);
+                i += LANES;
+            }
+            let mut total = sum(acc);
+            for j in body..n {
+                total += (a[j] as f64 - b[j] as f64).powi(2);
+            }
+            total
+        }
+
+        #[target_feature(enable = $feature)]
+        pub unsafe fn $cos(a: &[$t], b: &[$t]) -> (f64, f64, f64) {
+            let n = a.len().min(b.len());
+            let body = n - n % LANES;
+            let (mut ab, mut aa, mut bb) = (zero(), zero(), zero());
+            let mut i = 0;
+            while i < body {
+                let x = $load(a.as_ptr().add(i));
+                let y = $load(b.as_ptr().add(i));
+                ab = fma(x, y, ab);
+                aa = fma(x, x, aa);
+                bb = fma(y, y, bb);
+                i += LANES;
+            }
+            let (mut ab, mut aa, mut bb) = (sum(ab), sum(aa), sum(bb));
+            for j in body..n {
+                let (x, y) = (a[j] as f64, b[j] as f64);
+                ab += x * y;
+                aa += x * x;
+                bb += y * y;
+            }
+            (ab, aa, bb)
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 4;
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn load_f64(p: *const f64) -> __m256d {
+        _mm256_loadu_pd(p)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn load_f32(p: *const f32) -> __m256d {
+        _mm256_cvtps_pd(_mm_loadu_ps(p))
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn zero() -> __m256d {
+        _mm256_setzero_pd()
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn fma(a: __m256d, b: __m256d, acc: __m256d) -> __m256d {
+        _mm256_fmadd_pd(a, b, acc)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn sub(a: __m256d, b: __m256d) -> __m256d {
+        _mm256_sub_pd(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn sum(v: __m256d) -> f64 {
+        let pair = _mm_add_pd(_mm256_castpd256_pd128(v), _mm256_extractf128_pd(v, 1));
+        _mm_cvtsd_f64(_mm_add_sd(pair, _mm_unpackhi_pd(pair, pair)))
+    }
+
+    kernels!(
+        "avx2,fma",
+        f64,
+        load_f64,
+        dot_f64,
+        l2_squared_f64,
+        cosine_parts_f64
+    );
+    kernels!(
+        "avx2,fma",
+        f32,
+        load_f32,
+        dot_f32,
+        l2_squared_f32,
+        cosine_parts_f32
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+mod sse2 {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 2;
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load_f64(p: *const f64) -> __m128d {
+        _mm_loadu_pd(p)
+    }
+
+    // two f32 are 64 bits, load them into the low half and widen
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load_f32(p: *const f32) -> __m128d {
+        _mm_cvtps_pd(_mm_castsi128_ps(_mm_loadl_epi64(p as *const __m128i)))
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn zero() -> __m128d {
+        _mm_setzero_pd()
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn fma(a: __m128d, b: __m128d, acc: __m128d) -> __m128d {
+        _mm_add_pd(_mm_mul_pd(a, b), acc)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn sub(a: __m128d, b: __m128d) -> __m128d {
+        _mm_sub_pd(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum(v: __m128d) -> f64 {
+        _mm_cvtsd_f64(_mm_add_sd(v, _mm_unpackhi_pd(v, v)))
+    }
+
+    kernels!(
+        "sse2",
+        f64,
+        load_f64,
+        dot_f64,
+        l2_squared_f64,
+        cosine_parts_f64
+    );
+    kernels!(
+        "sse2",
+        f32,
+        load_f32,
+        dot_f32,
+        l2_squared_f32,
+        cosine_parts_f32
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    const LANES: usize = 2;
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load_f64(p: *const f64) -> float64x2_t {
+        vld1q_f64(p)
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn load_f32(p: *const f32) -> float64x2_t {
+        vcvt_f64_f32(vld1_f32(p))
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn zero() -> float64x2_t {
+        vdupq_n_f64(0.0)
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn fma(a: float64x2_t, b: float64x2_t, acc: float64x2_t) -> float64x2_t {
+        vfmaq_f64(acc, a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn sub(a: float64x2_t, b: float64x2_t) -> float64x2_t {
+        vsubq_f64(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn sum(v: float64x2_t) -> f64 {
+        vaddvq_f64(v)
+    }
+
+    kernels!(
+        "neon",
+        f64,
+        load_f64,
+        dot_f64,
+        l2_squared_f64,
+        cosine_parts_f64
+    );
+    kernels!(
+        "neon",
+        f32,
+        load_f32,
+        dot_f32,
+        l2_squared_f32,
+        cosine_parts_f32
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    // same answers, up to rounding from the different summation order
+    fn close(x: f64, y: f64) -> bool {
+        (x - y).abs() <= 1e-9 * (1.0 + x.abs())
+    }
+
+    #[test]
+    fn kernels_match_the_portable_loops() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        // both sides of every threshold, with and without a tail
+        for n in [0, 1, 3, 7, 8, 9, 15, 16, 17, 23, 24, 25, 128, 771] {
+            let a: Vec<f64> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let b: Vec<f64> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let a32: Vec<f32> = a.iter().map(|&x| x as f32).collect();
+            let b32: Vec<f32> = b.iter().map(|&x| x as f32).collect();
+
+            assert!(close(dot_f64(&a, &b), portable::dot(&a, &b)));
+            assert!(close(dot_f32(&a32, &b32), portable::dot(&a32, &b32)));
+            assert!(close(l2_squared_f64(&a, &b), portable::l2_squared(&a, &b)));
+            assert!(close(
+                l2_squared_f32(&a32, &b32),
+                portable::l2_squared(&a32, &b32)
+            ));
+            let (x, y) = (cosine_parts_f64(&a, &b), portable::cosine_parts(&a, &b));
+            assert!(close(x.0, y.0) && close(x.1, y.1) && close(x.2, y.2));
+            let (x, y) = (
+                cosine_parts_f32(&a32, &b32),
+                portable::cosine_parts(&a32, &b32),
+            );
+            assert!(close(x.0, y.0) && close(x.1, y.1) && close(x.2, y.2));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use log::{error, info};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::{resolve_node, search_graph, Command, SearchOptions, HELP};
+use vite::hnsw::Graph;
+use vite::output::{node_json, search_rows};
+
+/*
+The interpreter's commands over a unix socket, one command per line in and one
+json document per line out:
+    search [0.1, 0.2] 10 where price < 50  ->  [{"id": 3, "distance": 0.1}, ...]
+    get 3                                   ->  {"id": 3, "vector": [...], ...}
+    help                                    ->  {"commands": [...]}
+    quit                                    closes the connection
+Errors are {"error": "..."}. Every connection has its own thread, the lines
+they read are answered one at a time by the thread that owns the graph.
+*/
+pub fn serve(g: &Graph, path: &str) -> io::Result<()> {
+    // a socket left behind by an earlier server would make bind fail
+    if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let (requests, queue) = mpsc::channel::<(String, mpsc::Sender<Option<String>>)>();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let requests = requests.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = connection(stream, requests) {
+                            error!("connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("accept failed: {}", e),
+            }
+        }
+    });
+
+    for (line, reply) in queue {
+        let _ = reply.send(answer(g, &line));
+    }
+    Ok(())
+}
+
+fn connection(
+    stream: UnixStream,
+    requests: mpsc::Sender<(String, mpsc::Sender<Option<String>>)>,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let (reply, answer) = mpsc::channel();
+        if requests.send((line, reply)).is_err() {
+            break;
+        }
+        match answer.recv() {
+            Ok(Some(response)) => writeln!(writer, "{}", response)?,
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+// the response line for one command, None closes the connection
+fn answer(g: &Graph, line: &str) -> Option<String> {
+    info!("{}", line.trim());
+    let response: Value = match Command::parse(g, line) {
+        Ok(Command::Search(q, k, condition)) => {
+            let search = search_graph(g, &q, k, &SearchOptions::interactive(condition));
+            json!(search_rows(g, &search.results, false, false))
+        }
+        Ok(Command::Get(key_or_id)) => match resolve_node(g, &key_or_id) {
+            Some(id) => node_json(g, id),
+            None => json!({ "error": format!("no node {}", key_or_id) }),
+        },
+        Ok(Command::Help) => json!({ "commands": HELP }),
+        Ok(Command::Quit) => return None,
+        Ok(Command::Nothing) => json!({ "error": "empty command" }),
+        Err(e) => json!({ "error": e }),
+    };
+    Some(response.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, small_graph, temp_path};
+
+    #[test]
+    fn commands() {
+        let path = temp_path("socket.sock");
+        let server_path = path.to_str().unwrap().to_string();
+        thread::spawn(move || {
+            let mut g = small_graph();
+            g.insert_with_key(&[0.0, 1.0], "a", false).unwrap();
+            serve(&g, &server_path).unwrap();
+        });
+
+        let stream = connect(|| UnixStream::connect(&path));
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let mut request = |line: &str| -> Option<Value> {
+            writeln!(&stream, "{}", line).unwrap();
+            lines
+                .next()
+                .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        };
+
+        let search = request("search [0, 1] 1").unwrap();
+        assert_eq!(search[0]["key"], "a");
+        assert_eq!(request("get a").unwrap()["id"], 1);
+        assert!(request("get b").unwrap()["error"].is_string());
+        assert!(request("search [0, 1] x").unwrap()["error"].is_string());
+        assert!(request("search [0] 1").unwrap()["error"].is_string());
+        assert_eq!(
+            request("help").unwrap()["commands"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(request("quit"), None);
+        let _ = std::fs::remove_file(&path);
+    }
+}
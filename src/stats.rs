@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use crate::check::unreachable;
+use crate::hnsw::{Graph, Node};
+use crate::metric::Metric;
+use crate::vector::{ElementType, Vector};
+
+// size prefix + index + layers + length of codes + norm + flags
+const NODE_OVERHEAD: usize = 4 + 8 + 8 + 8 + 8 + 1;
+// end of nodes marker
+const TERMINATOR_SIZE: usize = 4;
+
+pub struct LayerStats {
+    pub nodes: usize,
+    // degree -> number of nodes with that many friends
+    pub degrees: BTreeMap<usize, usize>,
+    pub average_distance: f64,
+}
+
+pub struct GraphStats {
+    pub nodes: usize,
+    pub dimension: usize,
+    pub metric: Metric,
+    pub element_type: ElementType,
+    pub quantizer: &'static str,
+    pub codes_only: bool,
+    pub layer_count: usize,
+    pub m_l: f64,
+    pub m_max: usize,
+    pub m_max0: usize,
+    pub candidate_list_size: usize,
+    pub seed: u64,
+    pub entrence_point: Option<usize>,
+    pub unreachable: usize,
+    pub deleted: usize,
+    pub layers: Vec<LayerStats>,
+    // magic and table of contents, shared by every collection in the file
+    pub table_bytes: usize,
+    pub header_bytes: usize,
+    pub vector_bytes: usize,
+    pub link_bytes: usize,
+    pub code_bytes: usize,
+    // includes the field count every node has, even without a payload
+    pub payload_bytes: usize,
+    pub key_bytes: usize,
+    pub node_overhead_bytes: usize,
+    // size on disk, if the graph came from a file
+    pub file_bytes: Option<u64>,
+}
+
+impl GraphStats {
+    pub fn new(g: &Graph, table_bytes: usize, file_bytes: Option<u64>) -> Self {
+        let layer_count = g
+            .nodes
+            .iter()
+            .map(|n| n.borrow().friend_layers.len())
+            .max()
+            .unwrap_or(0)
+            .max(g.layer_count);
+
+        let mut layers: Vec<LayerStats> = (0..layer_count)
+            .map(|_| LayerStats {
+                nodes: 0,
+                degrees: BTreeMap::new(),
+                average_distance: 0.0,
+            })
+            .collect();
+        let mut distances = vec![(0.0, 0usize); layer_count];
+        let mut link_bytes = 0;
+        // a codes only graph is measured by what its codes decode to
+        let decoded: Vec<Vector> = if g.codes_only {
+            g.vectors()
+                .iter()
+                .map(|v| Vector::encode(g.element_type, v))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        for (position, node) in g.nodes.iter().enumerate() {
+            let node = node.borrow();
+            let (vector, norm) = measured(&decoded, position, &node);
+            for (l, friends) in node.friend_layers.iter().enumerate() {
+                layers[l].nodes += 1;
+                *layers[l].degrees.entry(friends.len()).or_insert(0) += 1;
+                link_bytes += 8 + 8 * friends.len();
+
+                for friend in friends {
+                    if let Some(friend_node) = g.nodes.get(friend.id()) {
+                        let friend_node = friend_node.borrow();
+                        let (friend_vector, friend_norm) =
+                            measured(&decoded, friend.id(), &friend_node);
+                        distances[l].0 +=
+                            g.metric
+                                .distance_with_norms(vector, norm, friend_vector, friend_norm);
+                        distances[l].1 += 1;
+                    }
+                }
+            }
+        }
+        for (layer, (sum, count)) in layers.iter_mut().zip(distances) {
+            if count > 0 {
+                layer.average_distance = sum / count as f64;
+            }
+        }
+
+        let entrence_point = g.entrence_point.id().filter(|&ep| ep < g.nodes.len());
+        let unreachable = match entrence_point {
+            Some(ep) => unreachable(g, ep).len(),
+            None => g.nodes.len(),
+        };
+
+        GraphStats {
+            nodes: g.nodes.len(),
+            dimension: g.dimension,
+            metric: g.metric,
+            element_type: g.element_type,
+            quantizer: g.quantizer.as_ref().map_or("none", |q| q.name()),
+            codes_only: g.codes_only,
+            layer_count: g.layer_count,
+            m_l: g.m_l,
+            m_max: g.m_max,
+            m_max0: g.m_max0,
+            candidate_list_size: g.candidate_list_size,
+            seed: g.seed,
+            entrence_point,
+            unreachable,
+            deleted: g.nodes.iter().filter(|n| n.borrow().deleted).count(),
+            layers,
+            table_bytes,
+            // graph data size + graph data
+            header_bytes: 4 + g.serialize().len(),
+            // nothing for the nodes of a codes only graph
+            vector_bytes: g
+                .nodes
+                .iter()
+                .map(|n| n.borrow().vector.len() * g.element_type.size())
+                .sum(),
+            link_bytes,
+            code_bytes: g.nodes.iter().map(|n| n.borrow().codes.len()).sum(),
+            payload_bytes: g
+                .nodes
+                .iter()
+                .map(|n| n.borrow().payload.serialized_size())
+                .sum(),
+            key_bytes: g
+                .nodes
+                .iter()
+                .filter_map(|n| n.borrow().key.as_ref().map(|key| 8 + key.len()))
+                .sum(),
+            node_overhead_bytes: g.nodes.len() * NODE_OVERHEAD + TERMINATOR_SIZE,
+            file_bytes,
+        }
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.table_bytes
+            + self.header_bytes
+            + self.vector_bytes
+            + self.link_bytes
+            + self.code_bytes
+            + self.payload_bytes
+            + self.key_bytes
+            + self.node_overhead_bytes
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "nodes": self.nodes,
+            "dimension": self.dimension,
+            "entrence_point": self.entrence_point,
+            "unreachable": self.unreachable,
+            "deleted": self.deleted,
+            "parameters": {
+                "metric": self.metric.name(),
+                "element_type": self.element_type.name(),
+                "quantizer": self.quantizer,
+                "codes_only": self.codes_only,
+                "layer_count": self.layer_count,
+                "m_l": self.m_l,
+                "m_max": self.m_max,
+                "m_max0": self.m_max0,
+                "candidate_list_size": self.candidate_list_size,
+                "seed": self.seed,
+            },
+            "layers": self.layers.iter().enumerate().map(|(i, l)| json!({
+                "layer": i,
+                "nodes": l.nodes,
+                "degrees": l.degrees.iter().map(|(d, c)| (d.to_string(), json!(c))).collect::<serde_json::Map<_, _>>(),
+                "average_distance": l.average_distance,
+            })).collect::<Vec<Value>>(),
+            "size": {
+                "table": self.table_bytes,
+                "header": self.header_bytes,
+                "vectors": self.vector_bytes,
+                "links": self.link_bytes,
+                "codes": self.code_bytes,
+                "payloads": self.payload_bytes,
+                "keys": self.key_bytes,
+                "node_overhead": self.node_overhead_bytes,
+                "total": self.total_bytes(),
+                "file": self.file_bytes,
+            },
+        })
+    }
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "nodes:\t{}", self.nodes)?;
+        writeln!(f, "dimension:\t{}", self.dimension)?;
+        match self.entrence_point {
+            Some(ep) => writeln!(f, "entrence point:\t{}", ep)?,
+            None => writeln!(f, "entrence point:\tmissing")?,
+        }
+        writeln!(f, "unreachable nodes:\t{}", self.unreachable)?;
+        writeln!(f, "deleted nodes:\t{}", self.deleted)?;
+
+        writeln!(f, "\nparameters")?;
+        writeln!(f, "  metric:\t{}", self.metric.name())?;
+        writeln!(f, "  element type:\t{}", self.element_type.name())?;
+        writeln!(f, "  quantizer:\t{}", self.quantizer)?;
+        writeln!(f, "  codes only:\t{}", self.codes_only)?;
+        writeln!(f, "  layer count:\t{}", self.layer_count)?;
+        writeln!(f, "  m_l:\t{}", self.m_l)?;
+        writeln!(f, "  m_max:\t{}", self.m_max)?;
+        writeln!(f, "  m_max0:\t{}", self.m_max0)?;
+        writeln!(f, "  candidate list size:\t{}", self.candidate_list_size)?;
+        writeln!(f, "  seed:\t{}", self.seed)?;
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            writeln!(f, "\nlayer {}", i)?;
+            writeln!(f, "  nodes:\t{}", layer.nodes)?;
+            writeln!(
+                f,
+                "  average neighbor distance:\t{:.6}",
+                layer.average_distance
+            )?;
+            // degree:number of nodes
+            write!(f, "  degrees:\t")?;
+            for (degree, count) in &layer.degrees {
+                write!(f, "{}:{} ", degree, count)?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "\nsize (bytes)")?;
+        writeln!(f, "  table of contents:\t{}", self.table_bytes)?;
+        writeln!(f, "  header:\t{}", self.header_bytes)?;
+        writeln!(f, "  vectors:\t{}", self.vector_bytes)?;
+        writeln!(f, "  links:\t{}", self.link_bytes)?;
+        writeln!(f, "  codes:\t{}", self.code_bytes)?;
+        writeln!(f, "  payloads:\t{}", self.payload_bytes)?;
+        writeln!(f, "  keys:\t{}", self.key_bytes)?;
+        writeln!(f, "  node overhead:\t{}", self.node_overhead_bytes)?;
+        writeln!(f, "  total:\t{}", self.total_bytes())?;
+        if let Some(file_bytes) = self.file_bytes {
+            writeln!(f, "  file:\t{}", file_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// a node's vector and norm, or what its codes decode to when the graph only kept those
+fn measured<'a>(decoded: &'a [Vector], position: usize, node: &'a Node) -> (&'a Vector, f64) {
+    match decoded.get(position) {
+        Some(vector) => (vector, vector.norm()),
+        None => (&node.vector, node.norm),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{table_size, GraphFile, DEFAULT_COLLECTION};
+    use crate::hnsw::tests::random_graph;
+    use crate::metric::Metric;
+    use crate::vector::ElementType;
+
+    #[test]
+    fn sizes_add_up_to_the_file() {
+        let g = random_graph(4, 200, Metric::Cosine, ElementType::F64, 1);
+        let path = std::env::temp_dir().join(format!("vite_stats_{}", std::process::id()));
+        GraphFile::create(path.to_str().unwrap().to_string())
+            .and_then(|mut file| file.write_all(&[(DEFAULT_COLLECTION, &g)]))
+            .unwrap();
+        let file_bytes = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        let stats = GraphStats::new(&g, table_size(&[DEFAULT_COLLECTION]), Some(file_bytes));
+        assert_eq!(stats.to_json()["size"]["total"], file_bytes);
+        assert_eq!(stats.layers[0].nodes, 200);
+        assert_eq!(stats.layers.len(), g.layer_count);
+        assert_eq!(stats.unreachable, 0);
+        assert!(stats.layers[0].average_distance > 0.0);
+    }
+}
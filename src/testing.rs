@@ -0,0 +1,71 @@
+/*
+Fixtures shared by the tests of the library and of the `vite` binary. The file
+is compiled into both, so everything is reached through `vite::`, which the
+library also answers to in its own tests.
+*/
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vite::file::{write_collection, GraphFile, DEFAULT_COLLECTION};
+use vite::hnsw::Graph;
+use vite::metric::Metric;
+use vite::vector::ElementType;
+
+// how long a server started by a test gets to come up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a two dimensional cosine graph holding only [1, 0]
+pub fn small_graph() -> Graph {
+    Graph::new(
+        &[1.0, 0.0],
+        4.0,
+        4,
+        8,
+        16,
+        Metric::Cosine,
+        ElementType::F64,
+        0,
+    )
+    .unwrap()
+}
+
+// a path in the temp dir that no other test, or test run, uses
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("vite_{}_{}", name, std::process::id()))
+}
+
+/*
+Writes `g` to a fresh file for a server to open. Servers keep their graph on a
+thread of their own (it isn't Send), so they read it from there and the test
+reads the file back with `reopen` to see that every write reached it.
+*/
+pub fn graph_file(name: &str, g: &Graph) -> String {
+    let path = temp_path(name).to_str().unwrap().to_string();
+    write_collection(&path, DEFAULT_COLLECTION, g).unwrap();
+    path
+}
+
+// reads back a file from `graph_file` and removes it
+pub fn reopen(path: &str) -> Box<Graph> {
+    let mut g = GraphFile::open(path.to_string())
+        .and_then(|mut file| file.read_collection(DEFAULT_COLLECTION))
+        .unwrap();
+    g.try_weaken_ep().unwrap();
+    g.try_link_friends().unwrap();
+    std::fs::remove_file(path).unwrap();
+    g
+}
+
+// retries `connect` while a server comes up on another thread, and fails the test if it never does
+pub fn connect<T, E: Debug>(mut connect: impl FnMut() -> Result<T, E>) -> T {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        match connect() {
+            Ok(connection) => return connection,
+            Err(e) if Instant::now() > deadline => panic!("could not connect: {:?}", e),
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
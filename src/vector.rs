@@ -0,0 +1,279 @@
+use half::{bf16, f16};
+
+use crate::simd;
+
+/*
+Vectors are handed to the graph as f64 and stored as whatever element type the
+graph was created with. Distances are always accumulated in f64.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ElementType {
+    F64,
+    F32,
+    F16,
+    BF16,
+}
+
+impl ElementType {
+    pub fn parse(s: &str) -> Option<ElementType> {
+        match s {
+            "f64" => Some(ElementType::F64),
+            "f32" => Some(ElementType::F32),
+            "f16" => Some(ElementType::F16),
+            "bf16" => Some(ElementType::BF16),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ElementType::F64 => "f64",
+            ElementType::F32 => "f32",
+            ElementType::F16 => "f16",
+            ElementType::BF16 => "bf16",
+        }
+    }
+
+    // bytes per vector component
+    pub fn size(&self) -> usize {
+        match self {
+            ElementType::F64 => 8,
+            ElementType::F32 => 4,
+            ElementType::F16 | ElementType::BF16 => 2,
+        }
+    }
+
+    // how the element type is stored in the file header
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ElementType::F64 => 0,
+            ElementType::F32 => 1,
+            ElementType::F16 => 2,
+            ElementType::BF16 => 3,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Option<ElementType> {
+        match b {
+            0 => Some(ElementType::F64),
+            1 => Some(ElementType::F32),
+            2 => Some(ElementType::F16),
+            3 => Some(ElementType::BF16),
+            _ => None,
+        }
+    }
+}
+
+pub trait Element: Copy {
+    fn to_f64(self) -> f64;
+    fn from_f64(x: f64) -> Self;
+    fn push_be_bytes(self, collect: &mut Vec<u8>);
+    fn from_be_slice(bytes: &[u8]) -> Self;
+
+    // distance kernels, f64 and f32 swap in the simd versions
+    fn dot(a: &[Self], b: &[Self]) -> f64 {
+        simd::portable::dot(a, b)
+    }
+    fn l2_squared(a: &[Self], b: &[Self]) -> f64 {
+        simd::portable::l2_squared(a, b)
+    }
+    fn cosine_parts(a: &[Self], b: &[Self]) -> (f64, f64, f64) {
+        simd::portable::cosine_parts(a, b)
+    }
+}
+
+impl Element for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+    fn from_f64(x: f64) -> Self {
+        x
+    }
+    fn push_be_bytes(self, collect: &mut Vec<u8>) {
+        collect.extend_from_slice(&self.to_be_bytes())
+    }
+    fn from_be_slice(bytes: &[u8]) -> Self {
+        f64::from_be_bytes(bytes.try_into().unwrap())
+    }
+    fn dot(a: &[Self], b: &[Self]) -> f64 {
+        simd::dot_f64(a, b)
+    }
+    fn l2_squared(a: &[Self], b: &[Self]) -> f64 {
+        simd::l2_squared_f64(a, b)
+    }
+    fn cosine_parts(a: &[Self], b: &[Self]) -> (f64, f64, f64) {
+        simd::cosine_parts_f64(a, b)
+    }
+}
+
+impl Element for f32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    fn from_f64(x: f64) -> Self {
+        x as f32
+    }
+    fn push_be_bytes(self, collect: &mut Vec<u8>) {
+        collect.extend_from_slice(&self.to_be_bytes())
+    }
+    fn from_be_slice(bytes: &[u8]) -> Self {
+        f32::from_be_bytes(bytes.try_into().unwrap())
+    }
+    fn dot(a: &[Self], b: &[Self]) -> f64 {
+        simd::dot_f32(a, b)
+    }
+    fn l2_squared(a: &[Self], b: &[Self]) -> f64 {
+        simd::l2_squared_f32(a, b)
+    }
+    fn cosine_parts(a: &[Self], b: &[Self]) -> (f64, f64, f64) {
+        simd::cosine_parts_f32(a, b)
+    }
+}
+
+impl Element for f16 {
+    fn to_f64(self) -> f64 {
+        self.to_f64()
+    }
+    fn from_f64(x: f64) -> Self {
+        f16::from_f64(x)
+    }
+    fn push_be_bytes(self, collect: &mut Vec<u8>) {
+        collect.extend_from_slice(&self.to_be_bytes())
+    }
+    fn from_be_slice(bytes: &[u8]) -> Self {
+        f16::from_be_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl Element for bf16 {
+    fn to_f64(self) -> f64 {
+        self.to_f64()
+    }
+    fn from_f64(x: f64) -> Self {
+        bf16::from_f64(x)
+    }
+    fn push_be_bytes(self, collect: &mut Vec<u8>) {
+        collect.extend_from_slice(&self.to_be_bytes())
+    }
+    fn from_be_slice(bytes: &[u8]) -> Self {
+        bf16::from_be_bytes(bytes.try_into().unwrap())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Vector {
+    F64(Box<[f64]>),
+    F32(Box<[f32]>),
+    F16(Box<[f16]>),
+    BF16(Box<[bf16]>),
+}
+
+fn encode<T: Element>(v: &[f64]) -> Box<[T]> {
+    v.iter().map(|&x| T::from_f64(x)).collect()
+}
+
+fn decode<T: Element>(bytes: &[u8], size: usize) -> Box<[T]> {
+    bytes.chunks(size).map(T::from_be_slice).collect()
+}
+
+impl Vector {
+    pub fn encode(element_type: ElementType, v: &[f64]) -> Vector {
+        match element_type {
+            ElementType::F64 => Vector::F64(v.into()),
+            ElementType::F32 => Vector::F32(encode(v)),
+            ElementType::F16 => Vector::F16(encode(v)),
+            ElementType::BF16 => Vector::BF16(encode(v)),
+        }
+    }
+
+    pub fn to_f64(&self) -> Vec<f64> {
+        match self {
+            Vector::F64(v) => v.to_vec(),
+            Vector::F32(v) => v.iter().map(|x| x.to_f64()).collect(),
+            Vector::F16(v) => v.iter().map(|x| x.to_f64()).collect(),
+            Vector::BF16(v) => v.iter().map(|x| x.to_f64()).collect(),
+        }
+    }
+
+    // |v|, nodes keep theirs so cosine doesn't recompute it on every comparison
+    pub fn norm(&self) -> f64 {
+        match self {
+            Vector::F64(v) => f64::dot(v, v).sqrt(),
+            Vector::F32(v) => f32::dot(v, v).sqrt(),
+            Vector::F16(v) => f16::dot(v, v).sqrt(),
+            Vector::BF16(v) => bf16::dot(v, v).sqrt(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Vector::F64(v) => v.len(),
+            Vector::F32(v) => v.len(),
+            Vector::F16(v) => v.len(),
+            Vector::BF16(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn serialize(&self, collect: &mut Vec<u8>) {
+        match self {
+            Vector::F64(v) => v.iter().for_each(|x| x.push_be_bytes(collect)),
+            Vector::F32(v) => v.iter().for_each(|x| x.push_be_bytes(collect)),
+            Vector::F16(v) => v.iter().for_each(|x| x.push_be_bytes(collect)),
+            Vector::BF16(v) => v.iter().for_each(|x| x.push_be_bytes(collect)),
+        }
+    }
+
+    // `bytes` must be exactly `dimension * element_type.size()` long
+    pub fn deserialize(element_type: ElementType, bytes: &[u8]) -> Vector {
+        let size = element_type.size();
+        match element_type {
+            ElementType::F64 => Vector::F64(decode(bytes, size)),
+            ElementType::F32 => Vector::F32(decode(bytes, size)),
+            ElementType::F16 => Vector::F16(decode(bytes, size)),
+            ElementType::BF16 => Vector::BF16(decode(bytes, size)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn element_types_round_trip() {
+        let v = [0.1, -2.5, 1000.0, 1.0 / 3.0];
+        for (element_type, tolerance) in [
+            (ElementType::F64, 0.0),
+            (ElementType::F32, 1e-7),
+            (ElementType::F16, 1e-3),
+            (ElementType::BF16, 1e-2),
+        ] {
+            assert_eq!(ElementType::parse(element_type.name()), Some(element_type));
+            assert_eq!(
+                ElementType::from_byte(element_type.to_byte()),
+                Some(element_type)
+            );
+
+            let vector = Vector::encode(element_type, &v);
+            let mut bytes = Vec::new();
+            vector.serialize(&mut bytes);
+            assert_eq!(bytes.len(), v.len() * element_type.size());
+
+            let read = Vector::deserialize(element_type, &bytes).to_f64();
+            for (a, b) in v.iter().zip(&read) {
+                assert!(
+                    (a - b).abs() <= tolerance * a.abs(),
+                    "{}: {} {}",
+                    element_type.name(),
+                    a,
+                    b
+                );
+            }
+        }
+        assert_eq!(ElementType::from_byte(4), None);
+    }
+}
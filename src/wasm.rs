@@ -0,0 +1,211 @@
+use std::io;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use crate::file::{GraphFile, DEFAULT_COLLECTION};
+use crate::hnsw::{knn_search, Graph};
+
+/*
+Read-only search in the browser, built with
+`wasm-pack build --target web -- --features wasm`:
+    const index = Index.load(await (await fetch("docs.vlite")).arrayBuffer());
+    const results = index.search(new Float64Array(query), 10);
+    results.ids, results.distances, index.key(id), index.payload(id)
+Files are written by the command line or the other bindings, the whole file is
+read into memory and then parsed like one on disk.
+
+Ids are u32 in JavaScript, the width of usize on wasm32, and graphs with more
+nodes than that are refused when they are loaded. Everything that doesn't touch
+a JavaScript value is plain Rust, so it is tested natively.
+*/
+#[wasm_bindgen]
+pub struct Index {
+    g: Box<Graph>,
+}
+
+// nearest first
+#[wasm_bindgen]
+pub struct SearchResults {
+    ids: Vec<u32>,
+    distances: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl SearchResults {
+    #[wasm_bindgen(getter)]
+    pub fn ids(&self) -> Vec<u32> {
+        self.ids.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn distances(&self) -> Vec<f64> {
+        self.distances.clone()
+    }
+}
+
+#[wasm_bindgen]
+impl Index {
+    // `collection` defaults to "default"
+    pub fn load(buffer: &ArrayBuffer, collection: Option<String>) -> Result<Index, JsError> {
+        let bytes = Uint8Array::new(buffer).to_vec();
+        Ok(Index::from_bytes(&bytes, collection.as_deref())?)
+    }
+
+    // the names of the collections in a file
+    pub fn collections(buffer: &ArrayBuffer) -> Result<Vec<String>, JsError> {
+        let bytes = Uint8Array::new(buffer).to_vec();
+        Ok(collection_names(&bytes)?)
+    }
+
+    // `ef` defaults to 20
+    pub fn search(
+        &self,
+        vector: &[f64],
+        k: usize,
+        ef: Option<usize>,
+    ) -> Result<SearchResults, JsError> {
+        self.search_results(vector, k, ef)
+            .map_err(|e| JsError::new(&e))
+    }
+
+    pub fn key(&self, id: usize) -> Option<String> {
+        self.g.nodes.get(id)?.borrow().key.clone()
+    }
+
+    // the payload as json text, undefined when the node has none
+    pub fn payload(&self, id: usize) -> Option<String> {
+        let node = self.g.nodes.get(id)?.borrow();
+        if node.payload.is_empty() {
+            return None;
+        }
+        Some(node.payload.to_json().to_string())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn dimension(&self) -> usize {
+        self.g.dimension
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn metric(&self) -> String {
+        self.g.metric.name().to_string()
+    }
+
+    // nodes in the graph, deleted ones included
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> usize {
+        self.g.nodes.len()
+    }
+}
+
+impl Index {
+    fn from_bytes(bytes: &[u8], collection: Option<&str>) -> io::Result<Index> {
+        let collection = collection.unwrap_or(DEFAULT_COLLECTION);
+        let mut g = GraphFile::from_bytes(bytes).read_collection(collection)?;
+        if u32::try_from(g.nodes.len()).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "more nodes than u32 ids can tell apart",
+            ));
+        }
+        g.try_weaken_ep()?;
+        g.try_link_friends()?;
+        Ok(Index { g })
+    }
+
+    fn search_results(
+        &self,
+        vector: &[f64],
+        k: usize,
+        ef: Option<usize>,
+    ) -> Result<SearchResults, String> {
+        if vector.len() != self.g.dimension {
+            return Err(format!(
+                "expected vectors of length {}, got {}",
+                self.g.dimension,
+                vector.len()
+            ));
+        }
+        let results = knn_search(&self.g, vector, k, ef.unwrap_or(20));
+        Ok(SearchResults {
+            // every id fits, `from_bytes` made sure of it
+            ids: results.iter().map(|r| r.id as u32).collect(),
+            distances: results.iter().map(|r| r.distance).collect(),
+        })
+    }
+}
+
+fn collection_names(bytes: &[u8]) -> io::Result<Vec<String>> {
+    let table = GraphFile::from_bytes(bytes).table()?;
+    Ok(table.into_iter().map(|entry| entry.name).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::write_collection;
+    use crate::hnsw::tests::random_graph;
+    use crate::metric::Metric;
+    use crate::payload::Payload;
+    use crate::testing::temp_path;
+    use crate::vector::ElementType;
+
+    #[test]
+    fn indexes() {
+        let mut g = random_graph(4, 50, Metric::Cosine, ElementType::F32, 0);
+        let half = g.insert_with_key(&[0.5; 4], "half", false).unwrap();
+        let payload = serde_json::json!({"title": "a", "tags": ["x"]});
+        g.nodes[half].borrow_mut().payload = Payload::from_json(&payload).unwrap();
+        let small = random_graph(2, 10, Metric::Euclidean, ElementType::F64, 1);
+        let path = temp_path("wasm.vlite");
+        let path = path.to_str().unwrap();
+        write_collection(path, DEFAULT_COLLECTION, &g).unwrap();
+        write_collection(path, "small", &small).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            collection_names(&bytes).unwrap(),
+            [DEFAULT_COLLECTION, "small"]
+        );
+        let index = Index::from_bytes(&bytes, None).unwrap();
+        assert_eq!(
+            (index.dimension(), index.metric(), index.size()),
+            (4, "cosine".to_string(), 51)
+        );
+        let small = Index::from_bytes(&bytes, Some("small")).unwrap();
+        assert_eq!((small.dimension(), small.size()), (2, 10));
+        assert!(Index::from_bytes(&bytes, Some("missing")).is_err());
+        assert!(Index::from_bytes(&bytes[..bytes.len() / 2], None).is_err());
+        assert!(collection_names(&bytes[..10]).is_err());
+
+        // the same results as searching the graph itself
+        let results = index.search_results(&[0.5; 4], 5, None).unwrap();
+        let expected = knn_search(&g, &[0.5; 4], 5, 20);
+        let ids: Vec<u32> = expected.iter().map(|r| r.id as u32).collect();
+        assert_eq!(results.ids(), ids);
+        assert_eq!(results.ids()[0], half as u32);
+        assert!(results.distances().windows(2).all(|w| w[0] <= w[1]));
+        assert!(index.search_results(&[0.5; 3], 5, None).is_err());
+        assert_eq!(
+            index
+                .search_results(&[0.5; 4], 3, Some(50))
+                .unwrap()
+                .ids()
+                .len(),
+            3
+        );
+
+        assert_eq!(index.key(half).as_deref(), Some("half"));
+        assert_eq!(index.key(0), None);
+        assert_eq!(index.key(1000), None);
+        let text = index.payload(half).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            payload
+        );
+        assert_eq!(index.payload(0), None);
+        assert_eq!(index.payload(1000), None);
+    }
+}